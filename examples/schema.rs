@@ -3,7 +3,11 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use calculator::msg::{HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg};
+use calculator::msg::{
+    AccumulatorAnswer, BatchAnswer, ConfigAnswer, FormulaAnswer, HandleAnswer, HandleMsg,
+    HistoryAnswer, HookAnswer, HookMsg, InitMsg, LegacyHandleAnswer, QueryAnswer, QueryMsg,
+    SessionAnswer, SheetAnswer, VariableAnswer,
+};
 use calculator::state::StoredCalculation;
 
 fn main() {
@@ -15,6 +19,17 @@ fn main() {
     export_schema(&schema_for!(InitMsg), &out_dir);
     export_schema(&schema_for!(HandleMsg), &out_dir);
     export_schema(&schema_for!(HandleAnswer), &out_dir);
+    export_schema(&schema_for!(LegacyHandleAnswer), &out_dir);
+    export_schema(&schema_for!(BatchAnswer), &out_dir);
+    export_schema(&schema_for!(HistoryAnswer), &out_dir);
+    export_schema(&schema_for!(ConfigAnswer), &out_dir);
+    export_schema(&schema_for!(VariableAnswer), &out_dir);
+    export_schema(&schema_for!(AccumulatorAnswer), &out_dir);
+    export_schema(&schema_for!(FormulaAnswer), &out_dir);
+    export_schema(&schema_for!(SheetAnswer), &out_dir);
+    export_schema(&schema_for!(SessionAnswer), &out_dir);
+    export_schema(&schema_for!(HookAnswer), &out_dir);
+    export_schema(&schema_for!(HookMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(QueryAnswer), &out_dir);
     export_schema(&schema_for!(StoredCalculation), &out_dir);
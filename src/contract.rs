@@ -1,31 +1,114 @@
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::iter;
+
 use cosmwasm_std::{
-    debug_print, to_binary, Api, Binary, Env, Extern, HandleResponse, HandleResult, HumanAddr,
-    InitResponse, InitResult, Querier, QueryResult, StdError, StdResult, Storage, Uint128,
+    debug_print, from_binary, log, plaintext_log, to_binary, Api, BankMsg, Binary, Coin, CosmosMsg,
+    Env, Extern, HandleResponse, HandleResult, HumanAddr, InitResponse, InitResult, LogAttribute,
+    Querier, QueryResult, StdError, StdResult, Storage, Uint128, WasmMsg,
 };
-use integer_sqrt::IntegerSquareRoot;
 use secret_toolkit::permit::{validate, Permit};
+use secret_toolkit::snip20::{register_receive_msg, transfer_msg};
+use secret_toolkit::utils::{pad_handle_result, pad_query_result};
 
+use crate::error::{private_error, ContractError, ContractResult};
+use crate::formula::{parse, parse_with_names, Expr};
+use crate::math::compute;
 use crate::msg::{
-    BinaryOp, CalculatorPermission, HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg,
-    QueryWithPermit, UnaryOp,
+    AccumulatorAnswer, AccumulatorOp, BatchAnswer, BatchItemAnswer, CalcOp, CalculatorPermission,
+    Callback, CellContent, CellInfo, ConfigAnswer, ConfigUpdate, FormulaAnswer, HandleAnswer,
+    HandleMsg, HistoryAnswer, HookAnswer, HookMsg, InitMsg, LegacyHandleAnswer, LogDetail,
+    LogSettings, Operand, PublicFormulaInfo, PublishFormulaMsg, QueryAnswer, QueryMsg,
+    QueryWithPermit, SessionAnswer, SheetAnswer, VariableAnswer, PRICED_ACTIONS,
 };
+use crate::sheet::{clear_cell, is_cell_name, update_cell, CellUpdate};
 use crate::state::{
-    append_calculation, get_calculations, get_constants, set_constants, Constants,
-    StoredCalculation,
+    add_payment_token, add_session_calculation, add_tag, append_calculation, apply_retention,
+    clear_calculations, delete_calculation as delete_stored_calculation, end_session,
+    get_account_stats, get_accumulator, get_active_calculation, get_calculation, get_calculations,
+    get_config, get_constants, get_fees, get_formula, get_global_stats, get_hooks,
+    get_payment_token, get_preferences, get_public_formula, get_public_formulas,
+    get_published_global_stats, get_redo_stack, get_session, get_session_calculations, get_sheet,
+    get_tagged_calculations, get_variables, publish_formula as store_public_formula,
+    publish_global_stats, remove_tag, replace_calculation, set_account_stats, set_accumulator,
+    set_block_height, set_config, set_constants, set_fees, set_formula, set_global_stats,
+    set_hooks, set_preferences, set_public_formula_deprecated, set_redo_stack, set_retention,
+    set_sheet, set_variables, start_session, Cell, Config, Constants, Effect, Formula, FormulaCall,
+    Hook, PaymentToken, Preferences, PublicFormula, PublishedGlobalStats, Retention,
+    StoredCalculation, DEFAULT_FEE_DENOM, DEFAULT_MAX_BATCH_SIZE, DEFAULT_MAX_HISTORY,
+    DEFAULT_MAX_VARIABLES, MAX_FORMULA_DESCRIPTION_LENGTH, MAX_FORMULA_NAME_LENGTH, MAX_HOOKS,
+    MAX_MEMO_LENGTH, MAX_SESSION_LABEL_LENGTH, MAX_TAGS, MAX_TAG_LENGTH, MAX_VARIABLE_NAME_LENGTH,
 };
 
+// Responses are padded to a multiple of this many bytes, so that their encrypted length doesn't
+// tell how large the numbers in them are.
+pub const BLOCK_SIZE: usize = 256;
+
+// the most bytes a callback message may have, once the result is in it
+pub const MAX_CALLBACK_MSG_LENGTH: usize = 1024;
+const RESULT_PLACEHOLDER: &str = "{result}";
+
 pub fn init<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    _msg: InitMsg,
+    msg: InitMsg,
 ) -> InitResult {
+    let max_history = msg.max_history.unwrap_or(DEFAULT_MAX_HISTORY);
+    if max_history == 0 {
+        return Err(ContractError::InvalidLimit {
+            name: "max_history".to_string(),
+        }
+        .into());
+    }
+    let max_batch_size = msg.max_batch_size.unwrap_or(DEFAULT_MAX_BATCH_SIZE);
+    if max_batch_size == 0 {
+        return Err(ContractError::InvalidLimit {
+            name: "max_batch_size".to_string(),
+        }
+        .into());
+    }
+    let max_variables = msg.max_variables.unwrap_or(DEFAULT_MAX_VARIABLES);
+    if max_variables == 0 {
+        return Err(ContractError::InvalidLimit {
+            name: "max_variables".to_string(),
+        }
+        .into());
+    }
+    let prices = msg.prices.unwrap_or_default();
+    check_prices(&prices)?;
+    let mut messages = vec![];
+    if let Some(token) = &msg.payment_token {
+        check_payment_token(deps, &env, token)?;
+        add_payment_token(&mut deps.storage, token)?;
+        messages.push(register_receive(&env, token)?);
+    }
+
     set_constants(
         &mut deps.storage,
         &Constants {
             contract_address: env.contract.address,
         },
     )?;
-    Ok(InitResponse::default())
+    set_config(
+        &mut deps.storage,
+        &Config {
+            admin: msg.admin.unwrap_or(env.message.sender),
+            max_history,
+            global_stats_enabled: msg.global_stats_enabled.unwrap_or(true),
+            max_batch_size,
+            max_variables,
+            privacy_mode: msg.privacy_mode.unwrap_or(false),
+            fee_denom: msg
+                .fee_denom
+                .unwrap_or_else(|| DEFAULT_FEE_DENOM.to_string()),
+            prices,
+            payment_token: msg.payment_token,
+        },
+    )?;
+    Ok(InitResponse {
+        messages,
+        log: vec![],
+    })
 }
 
 pub fn handle<S: Storage, A: Api, Q: Querier>(
@@ -33,545 +116,5661 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     env: Env,
     msg: HandleMsg,
 ) -> HandleResult {
+    set_block_height(&mut deps.storage, env.block.height);
+    publish_global_stats(&mut deps.storage, env.block.height)?;
+
+    let res = match dispatch_handle(deps, env, msg) {
+        Ok(res) => Ok(res),
+        Err(err) => Err(hide_error(&deps.storage, err)?),
+    };
+    pad_handle_result(res, BLOCK_SIZE)
+}
+
+fn dispatch_handle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: HandleMsg,
+) -> ContractResult<HandleResponse> {
+    let (env, msg, payment) = match msg {
+        HandleMsg::Receive {
+            sender,
+            from,
+            amount,
+            msg,
+        } => {
+            let (env, payment) = receive_payment(deps, env, sender, from, amount)?;
+            (env, from_binary(&msg)?, payment)
+        }
+        msg => {
+            let payment = Payment::Native(env.message.sent_funds.clone());
+            (env, msg, payment)
+        }
+    };
+    let action = msg.action();
+    let mut log = Log::new(get_preferences(&deps.storage, &env.message.sender)?.log);
+    let mut messages = vec![];
+    let hooks = get_hooks(&deps.storage, &env.message.sender)?;
+    let units = match &msg {
+        HandleMsg::Batch { ops, .. } => ops.len() as u128,
+        _ => 1,
+    };
+
+    messages.extend(charge_fee(deps, &env, action, units, payment)?);
+
     let res = match msg {
-        HandleMsg::Add(calculation) => add(deps, env, calculation)?,
-        HandleMsg::Sub(calculation) => sub(deps, env, calculation)?,
-        HandleMsg::Mul(calculation) => mul(deps, env, calculation)?,
-        HandleMsg::Div(calculation) => div(deps, env, calculation)?,
-        HandleMsg::Sqrt(calculation) => sqrt(deps, env, calculation)?,
+        HandleMsg::Add(calculation) => to_binary(&LegacyHandleAnswer(
+            calculate(
+                deps,
+                env,
+                Notes::default(),
+                CalcOp::Add(calculation).into(),
+                &mut log,
+            )?
+            .result,
+        ))?,
+        HandleMsg::Sub(calculation) => to_binary(&LegacyHandleAnswer(
+            calculate(
+                deps,
+                env,
+                Notes::default(),
+                CalcOp::Sub(calculation).into(),
+                &mut log,
+            )?
+            .result,
+        ))?,
+        HandleMsg::Mul(calculation) => to_binary(&LegacyHandleAnswer(
+            calculate(
+                deps,
+                env,
+                Notes::default(),
+                CalcOp::Mul(calculation).into(),
+                &mut log,
+            )?
+            .result,
+        ))?,
+        HandleMsg::Div(calculation) => to_binary(&LegacyHandleAnswer(
+            calculate(
+                deps,
+                env,
+                Notes::default(),
+                CalcOp::Div(calculation).into(),
+                &mut log,
+            )?
+            .result,
+        ))?,
+        HandleMsg::Sqrt(calculation) => to_binary(&LegacyHandleAnswer(
+            calculate(
+                deps,
+                env,
+                Notes::default(),
+                CalcOp::Sqrt(calculation).into(),
+                &mut log,
+            )?
+            .result,
+        ))?,
+        HandleMsg::Calculate {
+            op,
+            record,
+            session,
+            memo,
+            tags,
+            log: settings,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let notes = Notes::new(record, session, memo, tags)?.with_callback(callback.as_ref());
+            log.override_with(settings);
+
+            let answer = calculate(deps, env, notes, op, &mut log)?;
+            messages.extend(callback_message(callback.as_ref(), answer.result)?);
+            to_binary(&answer)?
+        }
+        HandleMsg::Batch {
+            ops,
+            atomic,
+            record,
+            session,
+            memo,
+            tags,
+            log: settings,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let notes = Notes::new(record, session, memo, tags)?.with_callback(callback.as_ref());
+            log.override_with(settings);
+
+            let answer = batch(deps, env, notes, ops, atomic, &mut log)?;
+            for result in answer.results.iter().filter_map(|item| item.result) {
+                messages.extend(callback_message(callback.as_ref(), result)?);
+            }
+            to_binary(&answer)?
+        }
+        HandleMsg::StartSession { label, .. } => to_binary(&begin_session(deps, env, label)?)?,
+        HandleMsg::EndSession { id, .. } => to_binary(&finish_session(deps, env, id)?)?,
+        HandleMsg::ClearHistory { .. } => to_binary(&clear_history(deps, env)?)?,
+        HandleMsg::TagCalculation { id, tags, .. } => {
+            to_binary(&tag_calculation(deps, env, id, tags)?)?
+        }
+        HandleMsg::DeleteCalculation { id, .. } => to_binary(&delete_calculation(deps, env, id)?)?,
+        HandleMsg::Undo { .. } => to_binary(&undo(deps, env)?)?,
+        HandleMsg::Redo { .. } => to_binary(&redo(deps, env)?)?,
+        HandleMsg::SetRetention {
+            max_entries,
+            ttl_blocks,
+            ..
+        } => to_binary(&set_history_retention(deps, env, max_entries, ttl_blocks)?)?,
+        HandleMsg::SetPreferences {
+            record_history,
+            log: settings,
+            ..
+        } => to_binary(&update_preferences(deps, env, record_history, settings)?)?,
+        HandleMsg::SetVar {
+            name,
+            value,
+            session,
+            ..
+        } => to_binary(&set_variable(deps, env, session, name, value, &mut log)?)?,
+        HandleMsg::IncrVar {
+            name,
+            amount,
+            session,
+            ..
+        } => to_binary(&increment_variable(
+            deps, env, session, name, amount, &mut log,
+        )?)?,
+        HandleMsg::DecrVar {
+            name,
+            amount,
+            session,
+            ..
+        } => to_binary(&decrement_variable(
+            deps, env, session, name, amount, &mut log,
+        )?)?,
+        HandleMsg::ClearVar { name, session, .. } => {
+            to_binary(&clear_variable(deps, env, session, name, &mut log)?)?
+        }
+        HandleMsg::Accumulate {
+            op,
+            operand,
+            session,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let answer = accumulate(deps, env, session, op, operand, &mut log)?;
+            if let AccumulatorAnswer::Accumulate { value, .. } = answer {
+                messages.extend(callback_message(callback.as_ref(), value)?);
+            }
+            to_binary(&answer)?
+        }
+        HandleMsg::ResetAccumulator { session, .. } => {
+            to_binary(&reset_accumulator(deps, env, session, &mut log)?)?
+        }
+        HandleMsg::DefineFormula {
+            name, params, expr, ..
+        } => to_binary(&define_formula(deps, env, name, params, expr)?)?,
+        HandleMsg::Invoke {
+            name,
+            args,
+            record,
+            session,
+            memo,
+            tags,
+            log: settings,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let notes = Notes::new(record, session, memo, tags)?.with_callback(callback.as_ref());
+            log.override_with(settings);
+
+            let answer = invoke_formula(deps, env, notes, name, args, &mut log)?;
+            messages.extend(callback_message(callback.as_ref(), answer.result)?);
+            to_binary(&answer)?
+        }
+        HandleMsg::PublishFormula(msg) => to_binary(&publish_formula(deps, env, msg)?)?,
+        HandleMsg::DeprecateFormula {
+            name,
+            version,
+            deprecated,
+            ..
+        } => to_binary(&deprecate_formula(deps, env, name, version, deprecated)?)?,
+        HandleMsg::InvokePublic {
+            author,
+            name,
+            version,
+            args,
+            record,
+            session,
+            memo,
+            tags,
+            log: settings,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let notes = Notes::new(record, session, memo, tags)?.with_callback(callback.as_ref());
+            log.override_with(settings);
+
+            let answer =
+                invoke_public_formula(deps, env, notes, author, name, version, args, &mut log)?;
+            messages.extend(callback_message(callback.as_ref(), answer.result)?);
+            to_binary(&answer)?
+        }
+        HandleMsg::SetCell {
+            cell,
+            content,
+            session,
+            callback,
+            ..
+        } => {
+            check_callback(deps, &env, callback.as_ref())?;
+            let answer = set_cell(deps, env, session, cell, content, &mut log)?;
+            // the set cell is always the first to change
+            let SheetAnswer::SetCell { changed } = &answer;
+            if let Some(cell) = changed.first() {
+                messages.extend(callback_message(callback.as_ref(), cell.value)?);
+            }
+            to_binary(&answer)?
+        }
+        HandleMsg::UpdateConfig(update) => to_binary(&update_config(deps, env, update)?)?,
+        HandleMsg::RegisterHook {
+            contract_addr,
+            code_hash,
+            ..
+        } => to_binary(&register_hook(deps, env, contract_addr, code_hash)?)?,
+        HandleMsg::UnregisterHook { contract_addr, .. } => {
+            to_binary(&unregister_hook(deps, env, contract_addr)?)?
+        }
+        HandleMsg::WithdrawFees { to, amount, .. } => {
+            let from_address = env.contract.address.clone();
+            let answer = withdraw_fees(deps, env, &amount)?;
+            let token = get_payment_token(&deps.storage, &HumanAddr::from(amount.denom.as_str()))?;
+            let msg = match token {
+                Some(token) => transfer_msg(
+                    to,
+                    amount.amount,
+                    None,
+                    None,
+                    BLOCK_SIZE,
+                    token.code_hash,
+                    token.contract_addr,
+                )?,
+                _ => BankMsg::Send {
+                    from_address,
+                    to_address: to,
+                    amount: vec![amount],
+                }
+                .into(),
+            };
+            messages.push(msg);
+            to_binary(&answer)?
+        }
+        HandleMsg::SetPaymentToken {
+            contract_addr,
+            code_hash,
+            ..
+        } => {
+            let token = PaymentToken {
+                contract_addr,
+                code_hash,
+            };
+            messages.push(register_receive(&env, &token)?);
+            to_binary(&set_payment_token(deps, env, token)?)?
+        }
+        HandleMsg::Receive { .. } => {
+            return Err(ContractError::InvalidPayment {
+                reason: "payments can't be nested".to_string(),
+            })
+        }
     };
+    for notification in &log.notifications {
+        messages.extend(hook_messages(&hooks, notification)?);
+    }
 
     Ok(HandleResponse {
-        messages: vec![],
-        log: vec![],
-        data: Some(to_binary(&res)?),
+        messages,
+        log: log.into_attributes(action),
+        data: Some(res),
     })
 }
 
-fn save_calculation<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    calculation: StoredCalculation,
-    env: Env,
-) -> StdResult<()> {
-    append_calculation(&mut deps.storage, &calculation, &env.message.sender)
+// What a handle is paid with: the native coins sent along with it, or tokens `from` sent through
+// `receive`.
+enum Payment {
+    Native(Vec<Coin>),
+    Token {
+        token: PaymentToken,
+        from: HumanAddr,
+        amount: Uint128,
+    },
+}
+
+// The message to run comes from `sender`, so it runs as `sender`.
+fn receive_payment<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    mut env: Env,
+    sender: HumanAddr,
+    from: HumanAddr,
+    amount: Uint128,
+) -> ContractResult<(Env, Payment)> {
+    let token = match get_config(&deps.storage)?.payment_token {
+        Some(token) if token.contract_addr == env.message.sender => token,
+        _ => {
+            return Err(ContractError::InvalidPayment {
+                reason: "only the payment token is accepted".to_string(),
+            })
+        }
+    };
+
+    env.message.sender = sender;
+    env.message.sent_funds = vec![];
+    Ok((
+        env,
+        Payment::Token {
+            token,
+            from,
+            amount,
+        },
+    ))
 }
 
-fn add<S: Storage, A: Api, Q: Querier>(
+// Takes the price of the action out of the payment, and returns the message that sends back what's
+// left. The payment only stays with the contract if the handle succeeds.
+fn charge_fee<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    env: Env,
-    calculation: BinaryOp,
-) -> StdResult<HandleAnswer> {
-    let (left_operand, right_operand) = (calculation.0, calculation.1);
-    let result = Uint128::from(
-        left_operand
+    env: &Env,
+    action: &str,
+    units: u128,
+    payment: Payment,
+) -> ContractResult<Option<CosmosMsg>> {
+    let config = get_config(&deps.storage)?;
+    let unit_price = config.prices.get(action).copied().unwrap_or_default();
+    let price = unit_price
+        .u128()
+        .checked_mul(units)
+        .map(Uint128)
+        .ok_or_else(|| ContractError::Overflow {
+            op: "fee".to_string(),
+        })?;
+    let (denom, funds) = match &payment {
+        Payment::Native(funds) => (config.fee_denom, funds.clone()),
+        Payment::Token { token, amount, .. } => {
+            let denom = token.contract_addr.to_string();
+            let funds = vec![Coin {
+                denom: denom.clone(),
+                amount: *amount,
+            }];
+            (denom, funds)
+        }
+    };
+
+    let mut due = price.u128();
+    let mut refund = vec![];
+    for coin in funds {
+        let mut amount = coin.amount.u128();
+        if coin.denom == denom {
+            let paid = min(due, amount);
+            due -= paid;
+            amount -= paid;
+        }
+        if amount > 0 {
+            refund.push(Coin {
+                denom: coin.denom,
+                amount: Uint128(amount),
+            });
+        }
+    }
+    if due > 0 {
+        return Err(ContractError::FeeNotPaid { price, denom });
+    }
+
+    if !price.is_zero() {
+        let mut fees = get_fees(&deps.storage)?;
+        let collected = fees.entry(denom).or_default();
+        *collected = collected
             .u128()
-            .checked_add(right_operand.u128())
-            .ok_or_else(|| StdError::generic_err("Overflow in Add operation"))?,
-    );
+            .checked_add(price.u128())
+            .map(Uint128)
+            .ok_or_else(|| ContractError::Overflow {
+                op: "fee".to_string(),
+            })?;
+        set_fees(&mut deps.storage, &fees)?;
+    }
 
-    let calculation = StoredCalculation {
-        left_operand,
-        right_operand: Some(right_operand),
-        operation: "Add".to_string(),
-        result,
+    if refund.is_empty() {
+        return Ok(None);
+    }
+    let msg = match payment {
+        Payment::Native(_) => BankMsg::Send {
+            from_address: env.contract.address.clone(),
+            to_address: env.message.sender.clone(),
+            amount: refund,
+        }
+        .into(),
+        Payment::Token { token, from, .. } => transfer_msg(
+            from,
+            refund[0].amount,
+            None,
+            None,
+            BLOCK_SIZE,
+            token.code_hash,
+            token.contract_addr,
+        )?,
     };
+    Ok(Some(msg))
+}
 
-    save_calculation(deps, calculation, env)?;
+fn check_prices(prices: &BTreeMap<String, Uint128>) -> ContractResult<()> {
+    match prices
+        .keys()
+        .find(|action| !PRICED_ACTIONS.contains(&action.as_str()))
+    {
+        Some(action) => Err(ContractError::UnknownAction {
+            action: action.clone(),
+        }),
+        None => Ok(()),
+    }
+}
 
-    debug_print("Add: saved history successfully");
-    Ok(HandleAnswer(result))
+fn check_payment_token<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    token: &PaymentToken,
+) -> ContractResult<()> {
+    check_contract(deps, env, &token.contract_addr, &token.code_hash).map_err(|reason| {
+        ContractError::InvalidPaymentToken {
+            reason: reason.to_string(),
+        }
+    })
 }
 
-fn sub<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
-    calculation: BinaryOp,
-) -> StdResult<HandleAnswer> {
-    let (left_operand, right_operand) = (calculation.0, calculation.1);
-    let result = Uint128::from(
-        left_operand
-            .u128()
-            .checked_sub(right_operand.u128())
-            .ok_or_else(|| StdError::generic_err("Underflow in Sub operation"))?,
-    );
+// tells the token to call `receive` when tokens are sent to the calculator
+fn register_receive(env: &Env, token: &PaymentToken) -> StdResult<CosmosMsg> {
+    register_receive_msg(
+        env.contract_code_hash.clone(),
+        None,
+        BLOCK_SIZE,
+        token.code_hash.clone(),
+        token.contract_addr.clone(),
+    )
+}
 
-    let calculation = StoredCalculation {
-        left_operand,
-        right_operand: Some(right_operand),
-        operation: "Sub".to_string(),
-        result,
+// Checked before calculating, so that a bad callback doesn't cost the calculation's gas.
+fn check_callback<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    callback: Option<&Callback>,
+) -> ContractResult<()> {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return Ok(()),
+    };
+    let invalid = |reason: &str| ContractError::InvalidCallback {
+        reason: reason.to_string(),
     };
 
-    save_calculation(deps, calculation, env)?;
+    check_recipient(deps, env, &callback.contract_addr, &callback.code_hash)?.map_err(invalid)?;
+    if !callback.msg_template.contains(RESULT_PLACEHOLDER) {
+        return Err(invalid("the message template must contain `{result}`"));
+    }
+    if callback.msg_template.len() > MAX_CALLBACK_MSG_LENGTH {
+        return Err(ContractError::CallbackTooLong {
+            max: MAX_CALLBACK_MSG_LENGTH,
+        });
+    }
 
-    debug_print("Sub: saved history successfully");
-    Ok(HandleAnswer(result))
+    Ok(())
 }
 
-fn mul<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
-    calculation: BinaryOp,
-) -> StdResult<HandleAnswer> {
-    let (left_operand, right_operand) = (calculation.0, calculation.1);
-    let result = Uint128::from(
-        left_operand
-            .u128()
-            .checked_mul(right_operand.u128())
-            .ok_or_else(|| StdError::generic_err("Overflow in Mul operation".to_string()))?,
-    );
+// Returns why the calculator can't send messages to the contract, if it can't.
+fn check_contract<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    contract_addr: &HumanAddr,
+    code_hash: &str,
+) -> Result<(), &'static str> {
+    if deps.api.canonical_address(contract_addr).is_err() {
+        return Err("bad contract address");
+    }
+    if *contract_addr == env.contract.address {
+        return Err("the calculator can't call itself back");
+    }
+    if code_hash.len() != 64 || !code_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("code hashes are 64 hexadecimal digits");
+    }
 
-    let calculation = StoredCalculation {
-        left_operand,
-        right_operand: Some(right_operand),
-        operation: "Mul".to_string(),
-        result,
+    Ok(())
+}
+
+// Callbacks and hooks are sent for accounts, so they can't go to a payment token, current or
+// previous, as they could spend the fees the calculator holds there.
+fn check_recipient<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    contract_addr: &HumanAddr,
+    code_hash: &str,
+) -> StdResult<Result<(), &'static str>> {
+    if get_payment_token(&deps.storage, contract_addr)?.is_some() {
+        return Ok(Err("payment tokens can't be called"));
+    }
+    Ok(check_contract(deps, env, contract_addr, code_hash))
+}
+
+fn callback_message(
+    callback: Option<&Callback>,
+    result: Uint128,
+) -> ContractResult<Option<CosmosMsg>> {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return Ok(None),
     };
 
-    save_calculation(deps, calculation, env)?;
+    let msg = callback
+        .msg_template
+        .replace(RESULT_PLACEHOLDER, &result.to_string());
+    if msg.len() > MAX_CALLBACK_MSG_LENGTH {
+        return Err(ContractError::CallbackTooLong {
+            max: MAX_CALLBACK_MSG_LENGTH,
+        });
+    }
 
-    debug_print("Mul: saved history successfully");
-    Ok(HandleAnswer(result))
+    Ok(Some(
+        WasmMsg::Execute {
+            contract_addr: callback.contract_addr.clone(),
+            callback_code_hash: callback.code_hash.clone(),
+            msg: Binary(msg.into_bytes()),
+            send: vec![],
+        }
+        .into(),
+    ))
 }
 
-fn div<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
-    calculation: BinaryOp,
-) -> StdResult<HandleAnswer> {
-    let (left_operand, right_operand) = (calculation.0, calculation.1);
+fn hook_messages(hooks: &[Hook], notification: &HookMsg) -> ContractResult<Vec<CosmosMsg>> {
+    let msg = to_binary(notification)?;
+    Ok(hooks
+        .iter()
+        .map(|hook| {
+            WasmMsg::Execute {
+                contract_addr: hook.contract_addr.clone(),
+                callback_code_hash: hook.code_hash.clone(),
+                msg: msg.clone(),
+                send: vec![],
+            }
+            .into()
+        })
+        .collect())
+}
+
+// What a handle reports about its calculations: the attributes it logs besides its `action`,
+// which depend on the log settings of the account or of the call, and the notifications of the
+// account's hooks.
+struct Log {
+    settings: LogSettings,
+    attributes: Vec<(&'static str, String)>,
+    notifications: Vec<HookMsg>,
+}
 
-    if right_operand == Uint128::zero() {
-        return Err(StdError::generic_err("Divisor can't be zero".to_string()));
+impl Log {
+    fn new(settings: LogSettings) -> Self {
+        Log {
+            settings,
+            attributes: vec![],
+            notifications: vec![],
+        }
     }
 
-    let result = Uint128::from(
-        left_operand
-            .u128()
-            .checked_div(right_operand.u128())
-            .ok_or_else(|| StdError::generic_err("Underflow in Div operation".to_string()))?,
-    );
+    fn override_with(&mut self, settings: Option<LogSettings>) {
+        if let Some(settings) = settings {
+            self.settings = settings;
+        }
+    }
 
-    let calculation = StoredCalculation {
-        left_operand,
-        right_operand: Some(right_operand),
-        operation: "Div".to_string(),
-        result,
-    };
+    fn calculation(&mut self, calculation: &StoredCalculation, id: Option<u64>) {
+        self.notifications.push(HookMsg::Calculation {
+            id,
+            operation: calculation.operation.clone(),
+            result: calculation.result,
+        });
+
+        let detail = self.settings.detail;
+        if detail == LogDetail::Action {
+            return;
+        }
+
+        self.attributes
+            .push(("operation", calculation.operation.clone()));
+        if let Some(id) = id {
+            self.attributes.push(("calculation_id", id.to_string()));
+        }
+        if detail != LogDetail::Full {
+            return;
+        }
+
+        match &calculation.formula {
+            Some(call) => {
+                let args: Vec<String> = call.args.iter().map(Uint128::to_string).collect();
+                self.attributes.push(("formula", call.name.clone()));
+                self.attributes.push(("args", args.join(",")));
+            }
+            None => {
+                self.attributes
+                    .push(("left_operand", calculation.left_operand.to_string()));
+                if let Some(right_operand) = calculation.right_operand {
+                    self.attributes
+                        .push(("right_operand", right_operand.to_string()));
+                }
+            }
+        }
+        self.attributes
+            .push(("result", calculation.result.to_string()));
+    }
 
-    save_calculation(deps, calculation, env)?;
+    // attributes are encrypted, so only the sender can read them, unless the account chose
+    // plaintext
+    fn into_attributes(self, action: &str) -> Vec<LogAttribute> {
+        let plaintext = self.settings.plaintext;
+        iter::once(("action", action.to_string()))
+            .chain(self.attributes)
+            .map(|(key, value)| {
+                if plaintext {
+                    plaintext_log(key, value)
+                } else {
+                    log(key, value)
+                }
+            })
+            .collect()
+    }
+}
 
-    debug_print("Div: saved history successfully");
-    Ok(HandleAnswer(result))
+// In privacy mode, errors are replaced with the code of their kind. Failed handles don't change
+// the config, so it can be read after the failure.
+fn hide_error<S: Storage>(storage: &S, err: ContractError) -> StdResult<StdError> {
+    if get_config(storage)?.privacy_mode {
+        Ok(private_error(&err))
+    } else {
+        Ok(err.into())
+    }
 }
 
-fn sqrt<S: Storage, A: Api, Q: Querier>(
+// Returns the id of the calculation in the history, unless the account chose not to record it.
+// Every calculation is reported in the log, recorded or not.
+fn save_calculation<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
+    calculation: StoredCalculation,
     env: Env,
-    calculation: UnaryOp,
-) -> StdResult<HandleAnswer> {
-    let radicand = calculation.0;
+    record: Option<bool>,
+    log: &mut Log,
+) -> ContractResult<Option<u64>> {
+    check_session(&deps.storage, &env.message.sender, calculation.session)?;
 
-    let result = Uint128::from(radicand.u128().integer_sqrt());
+    // calculations with an effect are always recorded, so that they can be undone
+    let record = calculation.effect.is_some()
+        || match record {
+            Some(record) => record,
+            None => get_preferences(&deps.storage, &env.message.sender)?.record_history,
+        };
 
-    let calculation = StoredCalculation {
-        left_operand: radicand,
-        right_operand: None,
-        operation: "Sqrt".to_string(),
-        result,
-    };
+    // calculations that aren't recorded still count
+    let mut stats = get_account_stats(&deps.storage, &env.message.sender)?;
+    let mut global_stats = get_global_stats(&deps.storage)?;
+    global_stats.add_calculation(&calculation.operation, stats.total == 0);
+    stats.add_calculation(&calculation.operation, calculation.block_height);
+    set_account_stats(&mut deps.storage, &env.message.sender, &stats)?;
+    set_global_stats(&mut deps.storage, &global_stats)?;
 
-    save_calculation(deps, calculation, env)?;
+    // a new calculation discards what was undone before it, even if it isn't recorded
+    if !get_redo_stack(&deps.storage, &env.message.sender)?.is_empty() {
+        set_redo_stack(&mut deps.storage, &env.message.sender, &[])?;
+    }
 
-    debug_print("Sqrt: saved history successfully");
-    Ok(HandleAnswer(result))
-}
+    if !record {
+        log.calculation(&calculation, None);
+        return Ok(None);
+    }
 
-pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
-    match msg {
-        QueryMsg::WithPermit { permit, query } => permit_queries(deps, permit, query),
+    let session = calculation.session;
+    let tags = calculation.tags.clone();
+    let id = append_calculation(&mut deps.storage, calculation.clone(), &env.message.sender)?;
+    log.calculation(&calculation, Some(id));
+    if let Some(session) = session {
+        add_session_calculation(&mut deps.storage, &env.message.sender, session, id)?;
+    }
+    for tag in &tags {
+        add_tag(&mut deps.storage, &env.message.sender, tag, id)?;
     }
+    Ok(Some(id))
 }
 
-fn permit_queries<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    permit: Permit<CalculatorPermission>,
-    query: QueryWithPermit,
-) -> QueryResult {
-    let contract_address = get_constants(&deps.storage)?.contract_address;
-
-    let account = validate(deps, "revoked_permits", &permit, &contract_address)?;
+// What the caller attached to an operation, recorded with each calculation it makes.
+#[derive(Clone, Default)]
+struct Notes {
+    // overrides the account's `record_history` preference
+    record: Option<bool>,
+    session: Option<u64>,
+    memo: Option<String>,
+    tags: Vec<String>,
+}
 
-    match query {
-        QueryWithPermit::CalculationHistory { page, page_size } => {
-            if !permit.check_permission(&CalculatorPermission::CalculationHistory) {
-                return Err(StdError::generic_err(format!(
-                    "No permission to query history, got permissions {:?}",
-                    permit.params.permissions
-                )));
+impl Notes {
+    fn new(
+        record: Option<bool>,
+        session: Option<u64>,
+        memo: Option<String>,
+        tags: Option<Vec<String>>,
+    ) -> ContractResult<Self> {
+        if let Some(memo) = &memo {
+            if memo.len() > MAX_MEMO_LENGTH {
+                return Err(ContractError::MemoTooLong {
+                    max: MAX_MEMO_LENGTH,
+                });
             }
+        }
 
-            query_calculation_history(deps, &account, page.unwrap_or(Uint128::zero()), page_size)
+        Ok(Notes {
+            record,
+            session,
+            memo,
+            tags: validate_tags(tags.unwrap_or_default())?,
+        })
+    }
+
+    // the receiver of a callback can look the calculation up, so it's always recorded
+    fn with_callback(mut self, callback: Option<&Callback>) -> Self {
+        if callback.is_some() {
+            self.record = Some(true);
         }
+        self
     }
 }
 
-pub fn query_calculation_history<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
+// Duplicate tags are dropped.
+fn validate_tags(tags: Vec<String>) -> ContractResult<Vec<String>> {
+    let mut unique: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+            return Err(ContractError::InvalidTag {
+                max_length: MAX_TAG_LENGTH,
+            });
+        }
+        if !unique.contains(&tag) {
+            unique.push(tag);
+        }
+    }
+    if unique.len() > MAX_TAGS {
+        return Err(ContractError::TooManyTags { max: MAX_TAGS });
+    }
+    Ok(unique)
+}
+
+fn check_session<S: Storage>(
+    storage: &S,
     account: &HumanAddr,
-    page: Uint128,
-    page_size: Uint128,
-) -> StdResult<Binary> {
-    let (calcs, total) = get_calculations(&deps.storage, account, page, page_size)?;
+    session: Option<u64>,
+) -> ContractResult<()> {
+    let id = match session {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    match get_session(storage, account, id)? {
+        None => Err(ContractError::SessionNotFound { id }),
+        Some(session) if session.end_block_height.is_some() => {
+            Err(ContractError::SessionEnded { id })
+        }
+        Some(_) => Ok(()),
+    }
+}
 
-    to_binary(&QueryAnswer::CalculationHistory {
-        calcs,
-        total: Some(total),
+fn calculate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    notes: Notes,
+    op: CalcOp<Operand>,
+    log: &mut Log,
+) -> ContractResult<HandleAnswer> {
+    let (left_reference, right_reference) = op.operands();
+    let left_reference = reference(left_reference);
+    let right_reference = right_reference.and_then(reference);
+
+    let op = op.try_map(|operand| resolve_operand(&deps.storage, &env.message.sender, operand))?;
+    let result = compute(&op)?;
+
+    let (left_operand, right_operand) = op.operands();
+    let record = notes.record;
+    let calculation = StoredCalculation {
+        id: 0,
+        left_operand: *left_operand,
+        right_operand: right_operand.copied(),
+        left_reference,
+        right_reference,
+        formula: None,
+        session: notes.session,
+        memo: notes.memo,
+        tags: notes.tags,
+        effect: None,
+        undone: false,
+        operation: op.name().to_string(),
+        result,
+        block_height: env.block.height,
+    };
+
+    let calculation_id = save_calculation(deps, calculation, env, record, log)?;
+    if calculation_id.is_some() {
+        debug_print(format!("{}: saved history successfully", op.name()));
+    }
+
+    Ok(HandleAnswer {
+        result,
+        calculation_id,
+        recorded: calculation_id.is_some(),
     })
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::test_utils::my_mock_dependencies;
-    use cosmwasm_std::testing::mock_env;
-    use cosmwasm_std::{coins, from_binary};
+fn reference(operand: &Operand) -> Option<Operand> {
+    match operand {
+        Operand::Value(_) => None,
+        reference => Some(reference.clone()),
+    }
+}
 
-    use super::*;
+fn resolve_operand<S: Storage>(
+    storage: &S,
+    account: &HumanAddr,
+    operand: Operand,
+) -> ContractResult<Uint128> {
+    let (calculation, not_found) = match operand {
+        Operand::Value(value) => return Ok(value),
+        Operand::Var(name) => {
+            return get_variables(storage, account)?
+                .remove(&name)
+                .ok_or(ContractError::VariableNotSet { name })
+        }
+        Operand::Last {} => (
+            get_active_calculation(storage, account, 0)?,
+            ContractError::EmptyHistory,
+        ),
+        Operand::Nth(back) => (
+            get_active_calculation(storage, account, back)?,
+            ContractError::NoCalculation { back },
+        ),
+        Operand::ById(id) => (
+            get_calculation(storage, account, id)?,
+            ContractError::CalculationNotFound { id },
+        ),
+    };
 
-    pub fn unpack_handle<S: Storage, A: Api, Q: Querier>(
-        deps: &mut Extern<S, A, Q>,
-        env: Env,
-        msg: HandleMsg,
-    ) -> HandleAnswer {
-        let res = handle(deps, env, msg).unwrap().data.unwrap();
-        from_binary(&res).unwrap()
-    }
+    calculation
+        .map(|calculation| calculation.result)
+        .ok_or(not_found)
+}
 
-    #[test]
-    fn bad_permit() -> Result<(), StdError> {
-        let mut deps = my_mock_dependencies(&coins(2, "token"));
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+// A failing item of an atomic batch fails the whole handle, which reverts the items before it.
+// Otherwise the error is reported in the item's answer and counted in the account's stats.
+fn batch<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    notes: Notes,
+    ops: Vec<CalcOp<Operand>>,
+    atomic: bool,
+    log: &mut Log,
+) -> ContractResult<BatchAnswer> {
+    let config = get_config(&deps.storage)?;
+    let max_batch_size = config.max_batch_size;
+    if ops.is_empty() {
+        return Err(ContractError::EmptyBatch);
+    }
+    if ops.len() > max_batch_size as usize {
+        return Err(ContractError::BatchTooLarge {
+            max: max_batch_size,
+        });
+    }
+    // otherwise every operation would fail on its own
+    check_session(&deps.storage, &env.message.sender, notes.session)?;
 
-        // invalid permit: the given signature signed chain_id="secret-4"
-        let bad_permit = r#"{
-            "params": {
-                "permit_name":"test",
-                "allowed_tokens": ["cosmos2contract"],
-                "chain_id": "secret-5",
-                "permissions": ["calculation_history"]
-            },
-            "signature": {
-                "pub_key": {
-                    "type": "tendermint/PubKeySecp256k1",
-                    "value":"A31nYb+/VgwXsjhgmdkRotRexaDmgblDlhQja/rtEKwW"
-                },
-                "signature":"QTVNw3CjT0wTRsPiWHpgZrP7lsDyzWFUv0qNLnhmptdRh0Kn40bGnmxqNapFQR4Iddd2B4kF1Vjyx1DM96sP+g=="
+    let mut results = Vec::with_capacity(ops.len());
+    let mut failures = 0;
+    for (index, op) in ops.into_iter().enumerate() {
+        match calculate(deps, env.clone(), notes.clone(), op, log) {
+            Ok(answer) => results.push(BatchItemAnswer {
+                result: Some(answer.result),
+                calculation_id: answer.calculation_id,
+                error: None,
+            }),
+            Err(err) if atomic => {
+                return Err(ContractError::BatchOperationFailed {
+                    index,
+                    error: Box::new(err),
+                });
             }
-        }"#;
+            Err(err) => {
+                failures += 1;
+                // the answer isn't an error, so it has to be hidden here
+                let err = if config.privacy_mode {
+                    private_error(&err)
+                } else {
+                    err.into()
+                };
+                results.push(BatchItemAnswer {
+                    result: None,
+                    calculation_id: None,
+                    error: Some(error_message(err)),
+                });
+            }
+        }
+    }
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&bad_permit).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(3),
-            },
-        };
+    if failures > 0 {
+        let mut stats = get_account_stats(&deps.storage, &env.message.sender)?;
+        for _ in 0..failures {
+            stats.add_failure();
+        }
+        set_account_stats(&mut deps.storage, &env.message.sender, &stats)?;
+    }
 
-        let res = query(&mut deps, msg);
-        assert_eq!(
-            res,
-            Err(StdError::generic_err(
-                "Failed to verify signatures for the given permit: IncorrectSignature",
-            ))
-        );
+    Ok(BatchAnswer { results })
+}
 
-        Ok(())
+fn error_message(err: StdError) -> String {
+    match err {
+        StdError::GenericErr { msg, .. } => msg,
+        err => err.to_string(),
     }
+}
 
-    const PERMIT: &str = r#"{
-        "params": {
-            "permit_name":"test",
-            "allowed_tokens": ["cosmos2contract"],
-            "chain_id": "secret-4",
-            "permissions": ["calculation_history"]
-        },
+fn begin_session<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    label: String,
+) -> ContractResult<SessionAnswer> {
+    if label.len() > MAX_SESSION_LABEL_LENGTH {
+        return Err(ContractError::SessionLabelTooLong {
+            max: MAX_SESSION_LABEL_LENGTH,
+        });
+    }
+
+    let id = start_session(
+        &mut deps.storage,
+        &env.message.sender,
+        label,
+        env.block.height,
+    )?;
+
+    Ok(SessionAnswer::StartSession { id })
+}
+
+fn finish_session<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+) -> ContractResult<SessionAnswer> {
+    end_session(&mut deps.storage, &env.message.sender, id, env.block.height)?;
+
+    Ok(SessionAnswer::EndSession { id })
+}
+
+fn clear_history<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> ContractResult<HistoryAnswer> {
+    let deleted = clear_calculations(&mut deps.storage, &env.message.sender)?;
+
+    debug_print(format!("ClearHistory: deleted {} calculations", deleted));
+    Ok(HistoryAnswer::ClearHistory { deleted })
+}
+
+fn delete_calculation<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+) -> ContractResult<HistoryAnswer> {
+    delete_stored_calculation(&mut deps.storage, &env.message.sender, id)?;
+
+    debug_print(format!("DeleteCalculation: deleted calculation {}", id));
+    Ok(HistoryAnswer::DeleteCalculation { id })
+}
+
+fn tag_calculation<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+    tags: Vec<String>,
+) -> ContractResult<HistoryAnswer> {
+    let tags = validate_tags(tags)?;
+    let account = env.message.sender;
+
+    let mut calculation = get_calculation(&deps.storage, &account, id)?
+        .ok_or(ContractError::CalculationNotFound { id })?;
+    for tag in calculation.tags.iter().filter(|tag| !tags.contains(tag)) {
+        remove_tag(&mut deps.storage, &account, tag, id)?;
+    }
+    for tag in tags.iter().filter(|tag| !calculation.tags.contains(tag)) {
+        add_tag(&mut deps.storage, &account, tag, id)?;
+    }
+
+    calculation.tags = tags.clone();
+    replace_calculation(&mut deps.storage, &account, &calculation)?;
+
+    Ok(HistoryAnswer::TagCalculation { id, tags })
+}
+
+fn set_history_retention<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    max_entries: Option<u32>,
+    ttl_blocks: Option<u64>,
+) -> ContractResult<HistoryAnswer> {
+    if max_entries == Some(0) {
+        return Err(ContractError::InvalidLimit {
+            name: "max_entries".to_string(),
+        });
+    }
+
+    let account = env.message.sender;
+    set_retention(
+        &mut deps.storage,
+        &account,
+        &Retention {
+            max_entries,
+            ttl_blocks,
+        },
+    )?;
+    apply_retention(&mut deps.storage, &account, env.block.height)?;
+
+    let max_history = get_config(&deps.storage)?.max_history;
+    Ok(HistoryAnswer::SetRetention {
+        max_entries: max_entries.map_or(max_history, |max_entries| max_entries.min(max_history)),
+        ttl_blocks,
+    })
+}
+
+fn update_preferences<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    record_history: bool,
+    log: Option<LogSettings>,
+) -> ContractResult<ConfigAnswer> {
+    let log = match log {
+        Some(log) => log,
+        None => get_preferences(&deps.storage, &env.message.sender)?.log,
+    };
+    set_preferences(
+        &mut deps.storage,
+        &env.message.sender,
+        &Preferences {
+            record_history,
+            log,
+        },
+    )?;
+
+    Ok(ConfigAnswer::SetPreferences {
+        record_history,
+        log,
+    })
+}
+
+fn register_hook<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    contract_addr: HumanAddr,
+    code_hash: String,
+) -> ContractResult<HookAnswer> {
+    check_recipient(deps, &env, &contract_addr, &code_hash)?.map_err(|reason| {
+        ContractError::InvalidHook {
+            reason: reason.to_string(),
+        }
+    })?;
+
+    let mut hooks = get_hooks(&deps.storage, &env.message.sender)?;
+    match hooks
+        .iter()
+        .position(|hook| hook.contract_addr == contract_addr)
+    {
+        Some(index) => hooks[index].code_hash = code_hash,
+        None if hooks.len() >= MAX_HOOKS => {
+            return Err(ContractError::TooManyHooks { max: MAX_HOOKS })
+        }
+        None => hooks.push(Hook {
+            contract_addr,
+            code_hash,
+        }),
+    }
+    set_hooks(&mut deps.storage, &env.message.sender, &hooks)?;
+
+    Ok(HookAnswer::RegisterHook { hooks })
+}
+
+fn unregister_hook<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    contract_addr: HumanAddr,
+) -> ContractResult<HookAnswer> {
+    let mut hooks = get_hooks(&deps.storage, &env.message.sender)?;
+    let count = hooks.len();
+    hooks.retain(|hook| hook.contract_addr != contract_addr);
+    if hooks.len() == count {
+        return Err(ContractError::HookNotFound { contract_addr });
+    }
+    set_hooks(&mut deps.storage, &env.message.sender, &hooks)?;
+
+    Ok(HookAnswer::UnregisterHook { hooks })
+}
+
+fn set_variable<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    name: String,
+    value: Uint128,
+    log: &mut Log,
+) -> ContractResult<VariableAnswer> {
+    let previous = store_variable(deps, &env.message.sender, &name, value)?;
+    record_variable_update(
+        deps,
+        env,
+        session,
+        "SetVar",
+        &name,
+        previous,
+        None,
+        Some(value),
+        log,
+    )?;
+
+    Ok(VariableAnswer::SetVar { name, value })
+}
+
+fn increment_variable<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    name: String,
+    amount: Uint128,
+    log: &mut Log,
+) -> ContractResult<VariableAnswer> {
+    let variables = get_variables(&deps.storage, &env.message.sender)?;
+    let value = variables
+        .get(&name)
+        .map_or(0, Uint128::u128)
+        .checked_add(amount.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Overflow {
+            op: "IncrVar".to_string(),
+        })?;
+    let previous = store_variable(deps, &env.message.sender, &name, value)?;
+    record_variable_update(
+        deps,
+        env,
+        session,
+        "IncrVar",
+        &name,
+        previous,
+        Some(amount),
+        Some(value),
+        log,
+    )?;
+
+    Ok(VariableAnswer::IncrVar { name, value })
+}
+
+fn decrement_variable<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    name: String,
+    amount: Uint128,
+    log: &mut Log,
+) -> ContractResult<VariableAnswer> {
+    let variables = get_variables(&deps.storage, &env.message.sender)?;
+    let value = variables
+        .get(&name)
+        .map_or(0, Uint128::u128)
+        .checked_sub(amount.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Underflow {
+            op: "DecrVar".to_string(),
+        })?;
+    let previous = store_variable(deps, &env.message.sender, &name, value)?;
+    record_variable_update(
+        deps,
+        env,
+        session,
+        "DecrVar",
+        &name,
+        previous,
+        Some(amount),
+        Some(value),
+        log,
+    )?;
+
+    Ok(VariableAnswer::DecrVar { name, value })
+}
+
+fn clear_variable<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    name: String,
+    log: &mut Log,
+) -> ContractResult<VariableAnswer> {
+    let mut variables = get_variables(&deps.storage, &env.message.sender)?;
+    let previous = match variables.remove(&name) {
+        Some(previous) => previous,
+        None => return Err(ContractError::VariableNotSet { name }),
+    };
+    set_variables(&mut deps.storage, &env.message.sender, &variables)?;
+    record_variable_update(
+        deps,
+        env,
+        session,
+        "ClearVar",
+        &name,
+        Some(previous),
+        None,
+        None,
+        log,
+    )?;
+
+    Ok(VariableAnswer::ClearVar { name })
+}
+
+// Variable updates are recorded so they can be undone. The left operand is the previous value and
+// the right one the amount it changed by, unset variables reading zero.
+#[allow(clippy::too_many_arguments)]
+fn record_variable_update<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    operation: &str,
+    name: &str,
+    previous: Option<Uint128>,
+    amount: Option<Uint128>,
+    value: Option<Uint128>,
+    log: &mut Log,
+) -> ContractResult<()> {
+    let calculation = StoredCalculation {
+        id: 0,
+        left_operand: previous.unwrap_or_default(),
+        right_operand: amount,
+        left_reference: Some(Operand::Var(name.to_string())),
+        right_reference: None,
+        formula: None,
+        session,
+        memo: None,
+        tags: vec![],
+        effect: Some(Effect::Variable {
+            name: name.to_string(),
+            previous,
+            value,
+        }),
+        undone: false,
+        operation: operation.to_string(),
+        result: value.unwrap_or_default(),
+        block_height: env.block.height,
+    };
+    save_calculation(deps, calculation, env, None, log)?;
+    Ok(())
+}
+
+// New variables count towards the account's limit, updating an existing one is always allowed.
+// Returns the previous value of the variable.
+fn store_variable<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    account: &HumanAddr,
+    name: &str,
+    value: Uint128,
+) -> ContractResult<Option<Uint128>> {
+    if name.is_empty() || name.len() > MAX_VARIABLE_NAME_LENGTH {
+        return Err(ContractError::InvalidVariableName {
+            max_length: MAX_VARIABLE_NAME_LENGTH,
+        });
+    }
+
+    let mut variables = get_variables(&deps.storage, account)?;
+    if !variables.contains_key(name) {
+        let max_variables = get_config(&deps.storage)?.max_variables;
+        if variables.len() >= max_variables as usize {
+            return Err(ContractError::TooManyVariables { max: max_variables });
+        }
+    }
+    let previous = variables.insert(name.to_string(), value);
+
+    set_variables(&mut deps.storage, account, &variables)?;
+    Ok(previous)
+}
+
+fn accumulate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    op: AccumulatorOp,
+    operand: Operand,
+    log: &mut Log,
+) -> ContractResult<AccumulatorAnswer> {
+    let reference = reference(&operand);
+    let operand = resolve_operand(&deps.storage, &env.message.sender, operand)?;
+
+    let previous = get_accumulator(&deps.storage, &env.message.sender)?;
+    let op = op.with_operands(previous, operand);
+    let value = compute(&op)?;
+    set_accumulator(&mut deps.storage, &env.message.sender, value)?;
+    record_accumulator_update(
+        deps,
+        env,
+        session,
+        op.name(),
+        Some(operand),
+        reference,
+        previous,
+        value,
+        log,
+    )?;
+
+    Ok(AccumulatorAnswer::Accumulate { previous, value })
+}
+
+fn reset_accumulator<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    log: &mut Log,
+) -> ContractResult<AccumulatorAnswer> {
+    let previous = get_accumulator(&deps.storage, &env.message.sender)?;
+    set_accumulator(&mut deps.storage, &env.message.sender, Uint128::zero())?;
+    record_accumulator_update(
+        deps,
+        env,
+        session,
+        "ResetAccumulator",
+        None,
+        None,
+        previous,
+        Uint128::zero(),
+        log,
+    )?;
+
+    Ok(AccumulatorAnswer::ResetAccumulator { previous })
+}
+
+// The left operand of the recorded calculation is the accumulator before the update, and its
+// result the accumulator after it.
+#[allow(clippy::too_many_arguments)]
+fn record_accumulator_update<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    operation: &str,
+    operand: Option<Uint128>,
+    reference: Option<Operand>,
+    previous: Uint128,
+    value: Uint128,
+    log: &mut Log,
+) -> ContractResult<()> {
+    let calculation = StoredCalculation {
+        id: 0,
+        left_operand: previous,
+        right_operand: operand,
+        left_reference: None,
+        right_reference: reference,
+        formula: None,
+        session,
+        memo: None,
+        tags: vec![],
+        effect: Some(Effect::Accumulator { previous, value }),
+        undone: false,
+        operation: operation.to_string(),
+        result: value,
+        block_height: env.block.height,
+    };
+    save_calculation(deps, calculation, env, None, log)?;
+    Ok(())
+}
+
+// The expression is parsed once here, invocations only evaluate the parsed formula.
+fn define_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    name: String,
+    params: Vec<String>,
+    expr: String,
+) -> ContractResult<FormulaAnswer> {
+    validate_formula_name(&name)?;
+
+    let body = parse(&expr, &params)?;
+    set_formula(
+        &mut deps.storage,
+        &env.message.sender,
+        &name,
+        &Formula {
+            params: params.clone(),
+            expr,
+            body,
+        },
+    )?;
+
+    Ok(FormulaAnswer::DefineFormula { name, params })
+}
+
+fn invoke_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    notes: Notes,
+    name: String,
+    args: Vec<Uint128>,
+    log: &mut Log,
+) -> ContractResult<HandleAnswer> {
+    let formula = match get_formula(&deps.storage, &env.message.sender, &name)? {
+        Some(formula) => formula,
+        None => return Err(ContractError::FormulaNotFound { name }),
+    };
+
+    let call = FormulaCall {
+        author: None,
+        name,
+        version: None,
+        args,
+    };
+    run_formula(deps, env, notes, &formula, call, log)
+}
+
+fn publish_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: PublishFormulaMsg,
+) -> ContractResult<FormulaAnswer> {
+    validate_formula_name(&msg.name)?;
+    if msg.description.len() > MAX_FORMULA_DESCRIPTION_LENGTH {
+        return Err(ContractError::DescriptionTooLong {
+            max: MAX_FORMULA_DESCRIPTION_LENGTH,
+        });
+    }
+
+    let body = parse(&msg.expr, &msg.params)?;
+    store_public_formula(
+        &mut deps.storage,
+        &PublicFormula {
+            author: env.message.sender,
+            name: msg.name.clone(),
+            version: msg.version,
+            description: msg.description,
+            deprecated: false,
+            formula: Formula {
+                params: msg.params,
+                expr: msg.expr,
+                body,
+            },
+        },
+    )?;
+
+    Ok(FormulaAnswer::PublishFormula {
+        name: msg.name,
+        version: msg.version,
+    })
+}
+
+fn deprecate_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    name: String,
+    version: u32,
+    deprecated: bool,
+) -> ContractResult<FormulaAnswer> {
+    set_public_formula_deprecated(
+        &mut deps.storage,
+        &env.message.sender,
+        &name,
+        version,
+        deprecated,
+    )?;
+
+    Ok(FormulaAnswer::DeprecateFormula {
+        name,
+        version,
+        deprecated,
+    })
+}
+
+// Deprecated versions can still be invoked, deprecation only tells users to move on.
+#[allow(clippy::too_many_arguments)]
+fn invoke_public_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    notes: Notes,
+    author: HumanAddr,
+    name: String,
+    version: u32,
+    args: Vec<Uint128>,
+    log: &mut Log,
+) -> ContractResult<HandleAnswer> {
+    let formula = match get_public_formula(&deps.storage, &author, &name, version)? {
+        Some(public_formula) => public_formula.formula,
+        None => return Err(ContractError::FormulaVersionNotFound { name, version }),
+    };
+
+    let call = FormulaCall {
+        author: Some(author),
+        name,
+        version: Some(version),
+        args,
+    };
+    run_formula(deps, env, notes, &formula, call, log)
+}
+
+fn run_formula<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    notes: Notes,
+    formula: &Formula,
+    call: FormulaCall,
+    log: &mut Log,
+) -> ContractResult<HandleAnswer> {
+    if call.args.len() != formula.params.len() {
+        return Err(ContractError::WrongArgumentCount {
+            name: call.name,
+            expected: formula.params.len(),
+            got: call.args.len(),
+        });
+    }
+
+    let result = formula.body.evaluate(&call.args)?;
+
+    let calculation = StoredCalculation {
+        id: 0,
+        left_operand: Uint128::zero(),
+        right_operand: None,
+        left_reference: None,
+        right_reference: None,
+        formula: Some(call),
+        session: notes.session,
+        memo: notes.memo,
+        tags: notes.tags,
+        effect: None,
+        undone: false,
+        operation: "Invoke".to_string(),
+        result,
+        block_height: env.block.height,
+    };
+
+    let calculation_id = save_calculation(deps, calculation, env, notes.record, log)?;
+
+    Ok(HandleAnswer {
+        result,
+        calculation_id,
+        recorded: calculation_id.is_some(),
+    })
+}
+
+fn validate_formula_name(name: &str) -> ContractResult<()> {
+    if name.is_empty() || name.len() > MAX_FORMULA_NAME_LENGTH {
+        return Err(ContractError::InvalidFormulaName {
+            max_length: MAX_FORMULA_NAME_LENGTH,
+        });
+    }
+    Ok(())
+}
+
+// Each changed cell is recorded as a calculation, with the values of the cells it references as
+// arguments. Only the calculation of the set cell has an effect, since undoing it recomputes the
+// others.
+fn set_cell<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    session: Option<u64>,
+    name: String,
+    content: CellContent,
+    log: &mut Log,
+) -> ContractResult<SheetAnswer> {
+    if !is_cell_name(&name) {
+        return Err(invalid_cell(&name));
+    }
+
+    let cell = parse_cell(content.clone())?;
+    let (previous, updates) =
+        write_cell(&mut deps.storage, &env.message.sender, &name, Some(cell))?;
+
+    // the set cell is always computed first, and the change is undone from the last record
+    let last = updates.len() - 1;
+    let mut change = Some((name, previous, content));
+    let mut records = 0;
+    let mut changed = Vec::with_capacity(updates.len());
+    for (index, update) in updates.into_iter().enumerate() {
+        let effect = if index == last {
+            change.take().map(|(cell, previous, content)| Effect::Cell {
+                cell,
+                previous,
+                content,
+                records,
+            })
+        } else {
+            None
+        };
+        let calculation = StoredCalculation {
+            id: 0,
+            left_operand: Uint128::zero(),
+            right_operand: None,
+            left_reference: None,
+            right_reference: None,
+            formula: Some(FormulaCall {
+                author: None,
+                name: update.cell.clone(),
+                version: None,
+                args: update.args,
+            }),
+            session,
+            memo: None,
+            tags: vec![],
+            effect,
+            undone: false,
+            operation: "Cell".to_string(),
+            result: update.value,
+            block_height: env.block.height,
+        };
+        if save_calculation(deps, calculation, env.clone(), None, log)?.is_some() {
+            records += 1;
+        }
+
+        changed.push(CellInfo {
+            cell: update.cell,
+            formula: update.formula,
+            value: update.value,
+        });
+    }
+
+    Ok(SheetAnswer::SetCell { changed })
+}
+
+fn invalid_cell(name: &str) -> ContractError {
+    ContractError::InvalidCellName {
+        name: name.to_string(),
+    }
+}
+
+fn parse_cell(content: CellContent) -> ContractResult<Cell> {
+    match content {
+        CellContent::Value(value) => Ok(Cell {
+            formula: None,
+            body: Expr::Value(value),
+            refs: vec![],
+            value,
+        }),
+        CellContent::Formula(expr) => {
+            let (body, refs) = parse_with_names(&expr)?;
+            if let Some(reference) = refs.iter().find(|reference| !is_cell_name(reference)) {
+                return Err(invalid_cell(reference));
+            }
+            Ok(Cell {
+                formula: Some(expr),
+                body,
+                refs,
+                value: Uint128::zero(),
+            })
+        }
+    }
+}
+
+// Sets a cell, or empties it if `cell` is `None`. Returns the previous content of the cell and
+// the cells whose value changed.
+fn write_cell<S: Storage>(
+    storage: &mut S,
+    account: &HumanAddr,
+    name: &str,
+    cell: Option<Cell>,
+) -> ContractResult<(Option<CellContent>, Vec<CellUpdate>)> {
+    let mut sheet = get_sheet(storage, account)?;
+    let previous = sheet.get(name).map(|cell| match &cell.formula {
+        Some(formula) => CellContent::Formula(formula.clone()),
+        None => CellContent::Value(cell.value),
+    });
+
+    let updates = match cell {
+        Some(cell) => update_cell(&mut sheet, name, cell)?,
+        None => clear_cell(&mut sheet, name)?,
+    };
+    set_sheet(storage, account, &sheet)?;
+
+    Ok((previous, updates))
+}
+
+fn undo<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> ContractResult<HistoryAnswer> {
+    let account = env.message.sender;
+    let calculation =
+        get_active_calculation(&deps.storage, &account, 0)?.ok_or(ContractError::NothingToUndo)?;
+
+    if let Some(effect) = &calculation.effect {
+        apply_effect(&mut deps.storage, &account, effect, true)?;
+    }
+    set_undone(&mut deps.storage, &account, &calculation, true)?;
+
+    let mut redo_stack = get_redo_stack(&deps.storage, &account)?;
+    redo_stack.push(calculation.id);
+    set_redo_stack(&mut deps.storage, &account, &redo_stack)?;
+
+    Ok(HistoryAnswer::Undo { id: calculation.id })
+}
+
+// Undone calculations that were deleted or fell out of the history since can't be redone, and
+// are skipped.
+fn redo<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> ContractResult<HistoryAnswer> {
+    let account = env.message.sender;
+    let mut redo_stack = get_redo_stack(&deps.storage, &account)?;
+
+    let calculation = loop {
+        let id = redo_stack.pop().ok_or(ContractError::NothingToRedo)?;
+        match get_calculation(&deps.storage, &account, id)? {
+            Some(calculation) if calculation.undone => break calculation,
+            _ => continue,
+        }
+    };
+
+    if let Some(effect) = &calculation.effect {
+        apply_effect(&mut deps.storage, &account, effect, false)?;
+    }
+    set_undone(&mut deps.storage, &account, &calculation, false)?;
+    set_redo_stack(&mut deps.storage, &account, &redo_stack)?;
+
+    Ok(HistoryAnswer::Redo { id: calculation.id })
+}
+
+// Marks a calculation as undone or not, along with the records of the change it is the last one
+// of. Records that were deleted or fell out of the history since are skipped.
+fn set_undone<S: Storage>(
+    storage: &mut S,
+    account: &HumanAddr,
+    calculation: &StoredCalculation,
+    undone: bool,
+) -> ContractResult<()> {
+    let records = match &calculation.effect {
+        Some(Effect::Cell { records, .. }) => *records as u64,
+        _ => 0,
+    };
+    for id in calculation.id.saturating_sub(records)..calculation.id {
+        if let Some(mut record) = get_calculation(storage, account, id)? {
+            record.undone = undone;
+            replace_calculation(storage, account, &record)?;
+        }
+    }
+
+    let mut calculation = calculation.clone();
+    calculation.undone = undone;
+    replace_calculation(storage, account, &calculation)?;
+    Ok(())
+}
+
+// Restores the state from before the effect if `undo`, or from after it otherwise. Variables are
+// restored even if the account has since reached its limit.
+fn apply_effect<S: Storage>(
+    storage: &mut S,
+    account: &HumanAddr,
+    effect: &Effect,
+    undo: bool,
+) -> ContractResult<()> {
+    match effect {
+        Effect::Variable {
+            name,
+            previous,
+            value,
+        } => {
+            let mut variables = get_variables(storage, account)?;
+            match if undo { previous } else { value } {
+                Some(value) => variables.insert(name.clone(), *value),
+                None => variables.remove(name),
+            };
+            set_variables(storage, account, &variables)?;
+            Ok(())
+        }
+        Effect::Cell {
+            cell,
+            previous,
+            content,
+            ..
+        } => {
+            let content = if undo {
+                previous.clone()
+            } else {
+                Some(content.clone())
+            };
+            let cell_content = content.map(parse_cell).transpose()?;
+            write_cell(storage, account, cell, cell_content)?;
+            Ok(())
+        }
+        Effect::Accumulator { previous, value } => {
+            set_accumulator(storage, account, if undo { *previous } else { *value })?;
+            Ok(())
+        }
+    }
+}
+
+fn update_config<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    update: ConfigUpdate,
+) -> ContractResult<ConfigAnswer> {
+    let mut config = get_config(&deps.storage)?;
+    if env.message.sender != config.admin {
+        return Err(ContractError::NotAdmin);
+    }
+
+    if let Some(admin) = update.admin {
+        config.admin = admin;
+    }
+    if let Some(max_history) = update.max_history {
+        if max_history == 0 {
+            return Err(ContractError::InvalidLimit {
+                name: "max_history".to_string(),
+            });
+        }
+        config.max_history = max_history;
+    }
+    if let Some(global_stats_enabled) = update.global_stats_enabled {
+        config.global_stats_enabled = global_stats_enabled;
+    }
+    if let Some(max_batch_size) = update.max_batch_size {
+        if max_batch_size == 0 {
+            return Err(ContractError::InvalidLimit {
+                name: "max_batch_size".to_string(),
+            });
+        }
+        config.max_batch_size = max_batch_size;
+    }
+    if let Some(max_variables) = update.max_variables {
+        if max_variables == 0 {
+            return Err(ContractError::InvalidLimit {
+                name: "max_variables".to_string(),
+            });
+        }
+        config.max_variables = max_variables;
+    }
+    if let Some(privacy_mode) = update.privacy_mode {
+        config.privacy_mode = privacy_mode;
+    }
+    if let Some(fee_denom) = update.fee_denom {
+        config.fee_denom = fee_denom;
+    }
+    if let Some(prices) = update.prices {
+        check_prices(&prices)?;
+        config.prices = prices;
+    }
+    set_config(&mut deps.storage, &config)?;
+
+    Ok(ConfigAnswer::UpdateConfig { config })
+}
+
+fn set_payment_token<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    payment_token: PaymentToken,
+) -> ContractResult<ConfigAnswer> {
+    let mut config = get_config(&deps.storage)?;
+    if env.message.sender != config.admin {
+        return Err(ContractError::NotAdmin);
+    }
+    check_payment_token(deps, &env, &payment_token)?;
+    add_payment_token(&mut deps.storage, &payment_token)?;
+
+    config.payment_token = Some(payment_token.clone());
+    set_config(&mut deps.storage, &config)?;
+
+    Ok(ConfigAnswer::SetPaymentToken { payment_token })
+}
+
+// Only takes the amount out of the collected fees, the caller sends it.
+fn withdraw_fees<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: &Coin,
+) -> ContractResult<ConfigAnswer> {
+    if env.message.sender != get_config(&deps.storage)?.admin {
+        return Err(ContractError::NotAdmin);
+    }
+
+    let mut fees = get_fees(&deps.storage)?;
+    let available = fees.get(&amount.denom).copied().unwrap_or_default();
+    if amount.amount > available {
+        return Err(ContractError::NotEnoughFees {
+            available,
+            denom: amount.denom.clone(),
+        });
+    }
+    let remaining = Uint128(available.u128() - amount.amount.u128());
+    fees.insert(amount.denom.clone(), remaining);
+    set_fees(&mut deps.storage, &fees)?;
+
+    Ok(ConfigAnswer::WithdrawFees {
+        amount: amount.clone(),
+        remaining,
+    })
+}
+
+pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
+    let res = match dispatch_query(deps, msg) {
+        Ok(res) => Ok(res),
+        Err(err) => Err(hide_error(&deps.storage, err)?),
+    };
+    pad_query_result(res, BLOCK_SIZE)
+}
+
+fn dispatch_query<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    msg: QueryMsg,
+) -> ContractResult<Binary> {
+    match msg {
+        QueryMsg::WithPermit { permit, query, .. } => permit_queries(deps, permit, query),
+        QueryMsg::GlobalStats { .. } => query_global_stats(deps),
+        QueryMsg::Compute { op, .. } => Ok(to_binary(&QueryAnswer::Compute {
+            result: compute(&op)?,
+        })?),
+        QueryMsg::ListFormulas {
+            page, page_size, ..
+        } => query_public_formulas(deps, page.unwrap_or(Uint128::zero()), page_size),
+        QueryMsg::Prices { .. } => {
+            let config = get_config(&deps.storage)?;
+            Ok(to_binary(&QueryAnswer::Prices {
+                denom: config.fee_denom,
+                prices: config.prices,
+            })?)
+        }
+    }
+}
+
+pub fn query_public_formulas<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: Uint128,
+    page_size: Uint128,
+) -> ContractResult<Binary> {
+    let (formulas, total) = get_public_formulas(&deps.storage, page, page_size)?;
+
+    Ok(to_binary(&QueryAnswer::ListFormulas {
+        formulas: formulas
+            .into_iter()
+            .map(|public_formula| PublicFormulaInfo {
+                author: public_formula.author,
+                name: public_formula.name,
+                version: public_formula.version,
+                description: public_formula.description,
+                deprecated: public_formula.deprecated,
+                params: public_formula.formula.params,
+                expr: public_formula.formula.expr,
+            })
+            .collect(),
+        total,
+    })?)
+}
+
+// Aggregated over all accounts, so it doesn't need a permit.
+pub fn query_global_stats<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> ContractResult<Binary> {
+    if !get_config(&deps.storage)?.global_stats_enabled {
+        return Err(ContractError::GlobalStatsDisabled);
+    }
+
+    let PublishedGlobalStats {
+        stats,
+        block_height,
+    } = get_published_global_stats(&deps.storage)?;
+
+    Ok(to_binary(&QueryAnswer::GlobalStats {
+        total: stats.total,
+        operations: stats.operations,
+        accounts: stats.accounts,
+        block_height,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    })?)
+}
+
+fn permit_queries<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    permit: Permit<CalculatorPermission>,
+    query: QueryWithPermit,
+) -> ContractResult<Binary> {
+    let contract_address = get_constants(&deps.storage)?.contract_address;
+
+    let account =
+        validate(deps, "revoked_permits", &permit, &contract_address).map_err(permit_error)?;
+
+    match query {
+        QueryWithPermit::CalculationHistory { page, page_size } => {
+            check_permission(&permit, CalculatorPermission::CalculationHistory, "history")?;
+            query_calculation_history(deps, &account, page.unwrap_or(Uint128::zero()), page_size)
+        }
+        QueryWithPermit::Calculation { id } => {
+            check_permission(&permit, CalculatorPermission::CalculationHistory, "history")?;
+            query_calculation(deps, &account, id)
+        }
+        QueryWithPermit::Stats {} => {
+            check_permission(&permit, CalculatorPermission::Stats, "stats")?;
+            query_stats(deps, &account)
+        }
+        QueryWithPermit::Variables {} => {
+            check_permission(&permit, CalculatorPermission::Variables, "variables")?;
+            query_variables(deps, &account)
+        }
+        QueryWithPermit::Sheet {} => {
+            check_permission(&permit, CalculatorPermission::Sheet, "sheet")?;
+            query_sheet(deps, &account)
+        }
+        QueryWithPermit::Accumulator {} => {
+            check_permission(&permit, CalculatorPermission::Accumulator, "accumulator")?;
+            query_accumulator(deps, &account)
+        }
+        QueryWithPermit::Session {
+            id,
+            page,
+            page_size,
+        } => {
+            check_permission(&permit, CalculatorPermission::CalculationHistory, "history")?;
+            query_session(
+                deps,
+                &account,
+                id,
+                page.unwrap_or(Uint128::zero()),
+                page_size,
+            )
+        }
+        QueryWithPermit::ByTag { tag, cursor, limit } => {
+            check_permission(&permit, CalculatorPermission::CalculationHistory, "history")?;
+            query_tagged_calculations(deps, &account, &tag, cursor, limit)
+        }
+    }
+}
+
+fn check_permission(
+    permit: &Permit<CalculatorPermission>,
+    permission: CalculatorPermission,
+    queried: &str,
+) -> ContractResult<()> {
+    if !permit.check_permission(&permission) {
+        return Err(ContractError::PermissionDenied {
+            needed: queried.to_string(),
+            granted: permit.params.permissions.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+// The toolkit reports every permit failure as a generic error, revocations are told apart by
+// their message.
+fn permit_error(err: StdError) -> ContractError {
+    match err {
+        StdError::GenericErr { msg, .. } if msg.contains("was revoked") => {
+            ContractError::PermitRevoked { reason: msg }
+        }
+        StdError::GenericErr { msg, .. } => ContractError::InvalidPermit { reason: msg },
+        err => err.into(),
+    }
+}
+
+pub fn query_calculation_history<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    page: Uint128,
+    page_size: Uint128,
+) -> ContractResult<Binary> {
+    let (calcs, total) = get_calculations(&deps.storage, account, page, page_size)?;
+
+    Ok(to_binary(&QueryAnswer::CalculationHistory {
+        calcs,
+        total: Some(total),
+    })?)
+}
+
+pub fn query_calculation<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    id: u64,
+) -> ContractResult<Binary> {
+    let calc = get_calculation(&deps.storage, account, id)?
+        .ok_or(ContractError::CalculationNotFound { id })?;
+
+    Ok(to_binary(&QueryAnswer::Calculation { calc })?)
+}
+
+pub fn query_tagged_calculations<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    tag: &str,
+    cursor: Option<u64>,
+    limit: u32,
+) -> ContractResult<Binary> {
+    let (calcs, next_cursor) = get_tagged_calculations(&deps.storage, account, tag, cursor, limit)?;
+
+    Ok(to_binary(&QueryAnswer::ByTag { calcs, next_cursor })?)
+}
+
+pub fn query_session<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    id: u64,
+    page: Uint128,
+    page_size: Uint128,
+) -> ContractResult<Binary> {
+    let session =
+        get_session(&deps.storage, account, id)?.ok_or(ContractError::SessionNotFound { id })?;
+    let calcs = get_session_calculations(&deps.storage, account, id, page, page_size)?;
+
+    Ok(to_binary(&QueryAnswer::Session { session, calcs })?)
+}
+
+pub fn query_stats<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+) -> ContractResult<Binary> {
+    let stats = get_account_stats(&deps.storage, account)?;
+
+    Ok(to_binary(&QueryAnswer::Stats {
+        total: stats.total,
+        operations: stats.operations,
+        first_block_height: stats.first_block_height,
+        last_block_height: stats.last_block_height,
+        failed_attempts: stats.failed_attempts,
+    })?)
+}
+
+pub fn query_variables<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+) -> ContractResult<Binary> {
+    Ok(to_binary(&QueryAnswer::Variables {
+        variables: get_variables(&deps.storage, account)?,
+    })?)
+}
+
+pub fn query_sheet<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+) -> ContractResult<Binary> {
+    let cells = get_sheet(&deps.storage, account)?
+        .into_iter()
+        .map(|(name, cell)| CellInfo {
+            cell: name,
+            formula: cell.formula,
+            value: cell.value,
+        })
+        .collect();
+
+    Ok(to_binary(&QueryAnswer::Sheet { cells })?)
+}
+
+pub fn query_accumulator<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+) -> ContractResult<Binary> {
+    Ok(to_binary(&QueryAnswer::Accumulator {
+        value: get_accumulator(&deps.storage, account)?,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::error::PrivateError;
+    use crate::msg::{BinaryOp, UnaryOp};
+    use crate::state::{
+        Session, GLOBAL_STATS_INTERVAL, PREFIX_CALCULATIONS, PREFIX_SESSION_CALCULATIONS,
+        PREFIX_SESSION_POSITIONS, PREFIX_TAGS, PREFIX_TAG_POSITIONS,
+    };
+    use crate::test_utils::my_mock_dependencies;
+    use cosmwasm_std::testing::mock_env;
+    use cosmwasm_std::{coin, coins, from_binary, ReadonlyStorage};
+    use cosmwasm_storage::ReadonlyPrefixedStorage;
+    use secret_toolkit::storage::AppendStore;
+
+    use super::*;
+
+    pub fn unpack_handle<S: Storage, A: Api, Q: Querier>(
+        deps: &mut Extern<S, A, Q>,
+        env: Env,
+        msg: HandleMsg,
+    ) -> LegacyHandleAnswer {
+        let res = handle(deps, env, msg).unwrap().data.unwrap();
+        from_binary(&res).unwrap()
+    }
+
+    pub fn query_history<S: Storage, A: Api, Q: Querier>(
+        deps: &Extern<S, A, Q>,
+        page_size: Uint128,
+    ) -> QueryAnswer {
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size,
+            },
+            padding: None,
+        };
+
+        from_binary(&query(deps, msg).unwrap()).unwrap()
+    }
+
+    fn history_ids<S: Storage, A: Api, Q: Querier>(
+        deps: &Extern<S, A, Q>,
+    ) -> (Vec<u64>, Option<Uint128>) {
+        match query_history(deps, Uint128(100)) {
+            QueryAnswer::CalculationHistory { calcs, total } => (
+                calcs.iter().map(|calculation| calculation.id).collect(),
+                total,
+            ),
+            other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_permit() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        // invalid permit: the given signature signed chain_id="secret-4"
+        let bad_permit = r#"{
+            "params": {
+                "permit_name":"test",
+                "allowed_tokens": ["cosmos2contract"],
+                "chain_id": "secret-5",
+                "permissions": ["calculation_history"]
+            },
+            "signature": {
+                "pub_key": {
+                    "type": "tendermint/PubKeySecp256k1",
+                    "value":"A31nYb+/VgwXsjhgmdkRotRexaDmgblDlhQja/rtEKwW"
+                },
+                "signature":"QTVNw3CjT0wTRsPiWHpgZrP7lsDyzWFUv0qNLnhmptdRh0Kn40bGnmxqNapFQR4Iddd2B4kF1Vjyx1DM96sP+g=="
+            }
+        }"#;
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&bad_permit).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let res = query(&mut deps, msg);
+        assert_eq!(
+            res,
+            Err(ContractError::InvalidPermit {
+                reason: "Failed to verify signatures for the given permit: IncorrectSignature"
+                    .to_string(),
+            }
+            .into())
+        );
+
+        Ok(())
+    }
+
+    const PERMIT: &str = r#"{
+        "params": {
+            "permit_name":"test",
+            "allowed_tokens": ["cosmos2contract"],
+            "chain_id": "secret-4",
+            "permissions": ["calculation_history"]
+        },
         "signature": {
             "pub_key": {
                 "type": "tendermint/PubKeySecp256k1",
                 "value":"A31nYb+/VgwXsjhgmdkRotRexaDmgblDlhQja/rtEKwW"
             },
-            "signature":"3FGAy2Sdjtcw8uLFBfYPoVNQ0FeNxhYPG7aXa9NkY+xC1RN5Yo8EIrPu523MrNfvCa5W/4Ni6Cv+3lvEw9dBfA=="
+            "signature":"3FGAy2Sdjtcw8uLFBfYPoVNQ0FeNxhYPG7aXa9NkY+xC1RN5Yo8EIrPu523MrNfvCa5W/4Ni6Cv+3lvEw9dBfA=="
+        }
+    }"#;
+
+    #[test]
+    fn add() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        // initial calculation history for an account should be unexistent
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![],
+                total: Some(Uint128::zero()),
+            }
+        );
+
+        let msg = HandleMsg::Add(BinaryOp(Uint128(12), Uint128(30)));
+
+        // it must be this key since that is who signed the previous query
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) = unpack_handle(&mut deps, env, msg);
+        assert_eq!(result, Uint128(42));
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(12),
+                    right_operand: Some(Uint128(30)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Add".to_string(),
+                    result: Uint128(42),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sub_underflow() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Sub(BinaryOp(Uint128(23), Uint128(113)));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let res = handle(&mut deps, env, msg);
+        assert_eq!(
+            res,
+            Err(ContractError::Underflow {
+                op: "Sub".to_string()
+            }
+            .into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sub() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Sub(BinaryOp(Uint128(123), Uint128(13)));
+
+        // it must be this key since that is who signed the query
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) = unpack_handle(&mut deps, env, msg);
+        assert_eq!(result, Uint128(110));
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(123),
+                    right_operand: Some(Uint128(13)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Sub".to_string(),
+                    result: Uint128(110),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mul() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Mul(BinaryOp(Uint128(23), Uint128(50)));
+
+        // it must be this key since that is who signed the query
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) = unpack_handle(&mut deps, env, msg);
+        assert_eq!(result, Uint128(1150));
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(23),
+                    right_operand: Some(Uint128(50)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Mul".to_string(),
+                    result: Uint128(1150),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn div() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Div(BinaryOp(Uint128(23), Uint128(50)));
+
+        // it must be this key since that is who signed the query
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) = unpack_handle(&mut deps, env, msg);
+        assert_eq!(result, Uint128(0));
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(3),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(23),
+                    right_operand: Some(Uint128(50)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Div".to_string(),
+                    result: Uint128(0),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn div_by_zero() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Div(BinaryOp(Uint128(23), Uint128(0)));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+
+        let res = handle(&mut deps, env, msg);
+        assert_eq!(
+            res,
+            Err(ContractError::DivisionByZero.into()),
+            "failed raising error for divisio by zero"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sqrt() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = HandleMsg::Sqrt(UnaryOp(Uint128(17)));
+
+        // it must be this key since that is who signed the query
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) = unpack_handle(&mut deps, env, msg);
+        assert_eq!(result, Uint128(4));
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(&PERMIT).unwrap(),
+            query: QueryWithPermit::CalculationHistory {
+                page: None,
+                page_size: Uint128(4),
+            },
+            padding: None,
+        };
+
+        let raw_res = query(&mut deps, msg)?;
+        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
+        let deserialized_result: QueryAnswer =
+            serde_json::from_str(response_string.as_str()).unwrap();
+        println!("the result is: {:?}", deserialized_result);
+        assert_eq!(
+            deserialized_result,
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(17),
+                    right_operand: None,
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Sqrt".to_string(),
+                    result: Uint128(4),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+        Ok(())
+    }
+    #[test]
+    fn delete_calculation() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for msg in [
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+            HandleMsg::Mul(BinaryOp(Uint128(3), Uint128(4))),
+            HandleMsg::Sqrt(UnaryOp(Uint128(25))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(
+            &mut deps,
+            env,
+            HandleMsg::DeleteCalculation {
+                id: 1,
+                padding: None,
+            },
+        )?;
+
+        assert_eq!(
+            query_history(&deps, Uint128(10)),
+            QueryAnswer::CalculationHistory {
+                calcs: vec![
+                    StoredCalculation {
+                        id: 2,
+                        left_operand: Uint128(25),
+                        right_operand: None,
+                        left_reference: None,
+                        right_reference: None,
+                        formula: None,
+                        session: None,
+                        memo: None,
+                        tags: vec![],
+                        effect: None,
+                        undone: false,
+                        operation: "Sqrt".to_string(),
+                        result: Uint128(5),
+                        block_height: 12345,
+                    },
+                    StoredCalculation {
+                        id: 0,
+                        left_operand: Uint128(1),
+                        right_operand: Some(Uint128(2)),
+                        left_reference: None,
+                        right_reference: None,
+                        formula: None,
+                        session: None,
+                        memo: None,
+                        tags: vec![],
+                        effect: None,
+                        undone: false,
+                        operation: "Add".to_string(),
+                        result: Uint128(3),
+                        block_height: 12345,
+                    },
+                ],
+                total: Some(Uint128(2)),
+            }
+        );
+
+        // a calculation can't be deleted twice
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let res = handle(
+            &mut deps,
+            env,
+            HandleMsg::DeleteCalculation {
+                id: 1,
+                padding: None,
+            },
+        );
+        assert_eq!(
+            res,
+            Err(ContractError::CalculationNotFound { id: 1 }.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_history() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for msg in [
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+            HandleMsg::Sub(BinaryOp(Uint128(4), Uint128(3))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let res = handle(&mut deps, env, HandleMsg::ClearHistory { padding: None })?;
+        let answer: HistoryAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer, HistoryAnswer::ClearHistory { deleted: 2 });
+
+        assert_eq!(
+            query_history(&deps, Uint128(10)),
+            QueryAnswer::CalculationHistory {
+                calcs: vec![],
+                total: Some(Uint128::zero()),
+            }
+        );
+
+        // the deleted calculations must not be left in storage
+        let store = ReadonlyPrefixedStorage::multilevel(
+            &[PREFIX_CALCULATIONS, b"qcYLPHTmmt6mhJpcp3UN"],
+            &deps.storage,
+        );
+        let store = AppendStore::<Option<StoredCalculation>, _>::attach(&store).unwrap()?;
+        assert!(store.iter().all(|calculation| calculation == Ok(None)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_ring_buffer() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = InitMsg {
+            max_history: Some(2),
+            ..InitMsg::default()
+        };
+        init(&mut deps, env, msg)?;
+
+        for radicand in [4, 9, 16] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(radicand))))?;
+        }
+
+        let (ids, total) = history_ids(&deps);
+        assert_eq!(ids, vec![2, 1]);
+        assert_eq!(total, Some(Uint128(2)));
+
+        // the oldest slot was overwritten instead of growing the store
+        let store = ReadonlyPrefixedStorage::multilevel(
+            &[PREFIX_CALCULATIONS, b"qcYLPHTmmt6mhJpcp3UN"],
+            &deps.storage,
+        );
+        let store = AppendStore::<Option<StoredCalculation>, _>::attach(&store).unwrap()?;
+        assert_eq!(store.len(), 2);
+
+        // an account can only lower the limit set by the admin
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetRetention {
+            max_entries: Some(1),
+            ttl_blocks: None,
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+
+        let (ids, total) = history_ids(&deps);
+        assert_eq!(ids, vec![2]);
+        assert_eq!(total, Some(Uint128(1)));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetRetention {
+            max_entries: Some(5),
+            ttl_blocks: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: HistoryAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            HistoryAnswer::SetRetention {
+                max_entries: 2,
+                ttl_blocks: None,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_growth() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("admin", &coins(2, "token"));
+        let msg = InitMsg {
+            max_history: Some(2),
+            ..InitMsg::default()
+        };
+        init(&mut deps, env, msg)?;
+
+        // wraps around the two slots
+        for radicand in [4, 9, 16] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(radicand))))?;
+        }
+
+        let env = mock_env("admin", &coins(2, "token"));
+        let msg = HandleMsg::UpdateConfig(ConfigUpdate {
+            max_history: Some(4),
+            ..ConfigUpdate::default()
+        });
+        handle(&mut deps, env, msg)?;
+
+        for radicand in [25, 36] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(radicand))))?;
+        }
+
+        // nothing inside the new window was evicted
+        let (ids, total) = history_ids(&deps);
+        assert_eq!(ids, vec![4, 3, 2, 1]);
+        assert_eq!(total, Some(Uint128(4)));
+        match query_history(&deps, Uint128(100)) {
+            QueryAnswer::CalculationHistory { calcs, .. } => {
+                let results: Vec<Uint128> = calcs.iter().map(|calc| calc.result).collect();
+                assert_eq!(
+                    results,
+                    vec![Uint128(6), Uint128(5), Uint128(4), Uint128(3)]
+                );
+            }
+            other => panic!("unexpected answer {:?}", other),
+        }
+
+        // the entries that were already stored stay in their slots
+        fn slot_ids<S: Storage>(storage: &S) -> StdResult<Vec<Option<u64>>> {
+            let store = ReadonlyPrefixedStorage::multilevel(
+                &[PREFIX_CALCULATIONS, b"qcYLPHTmmt6mhJpcp3UN"],
+                storage,
+            );
+            let store = AppendStore::<Option<StoredCalculation>, _>::attach(&store).unwrap()?;
+            store
+                .iter()
+                .map(|calculation| Ok(calculation?.map(|calculation| calculation.id)))
+                .collect()
+        }
+        assert_eq!(
+            slot_ids(&deps.storage)?,
+            vec![Some(2), Some(1), Some(3), Some(4)]
+        );
+
+        // and the ring keeps wrapping around in id order
+        for radicand in [49, 64] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(radicand))))?;
+        }
+        let (ids, _) = history_ids(&deps);
+        assert_eq!(ids, vec![6, 5, 4, 3]);
+        assert_eq!(
+            slot_ids(&deps.storage)?,
+            vec![Some(6), Some(5), Some(3), Some(4)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_ttl() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetRetention {
+            max_entries: None,
+            ttl_blocks: Some(10),
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+
+        for (height, radicand) in [(100, 4), (105, 9)] {
+            let mut env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            env.block.height = height;
+            handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(radicand))))?;
+        }
+
+        // another account moves the chain forward, expiring the first calculation
+        let mut env = mock_env("someone else", &coins(2, "token"));
+        env.block.height = 111;
+        handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(1))))?;
+
+        let (ids, total) = history_ids(&deps);
+        assert_eq!(ids, vec![1]);
+        assert_eq!(total, Some(Uint128(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_config() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("admin", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::UpdateConfig(ConfigUpdate {
+            max_history: Some(10),
+            ..ConfigUpdate::default()
+        });
+        let res = handle(&mut deps, env, msg);
+        assert_eq!(res, Err(ContractError::NotAdmin.into()));
+
+        let zero_limits = [
+            (
+                "max_history",
+                ConfigUpdate {
+                    max_history: Some(0),
+                    ..ConfigUpdate::default()
+                },
+            ),
+            (
+                "max_batch_size",
+                ConfigUpdate {
+                    max_batch_size: Some(0),
+                    ..ConfigUpdate::default()
+                },
+            ),
+            (
+                "max_variables",
+                ConfigUpdate {
+                    max_variables: Some(0),
+                    ..ConfigUpdate::default()
+                },
+            ),
+        ];
+        for (name, update) in zero_limits {
+            let env = mock_env("admin", &coins(2, "token"));
+            assert_eq!(
+                handle(&mut deps, env, HandleMsg::UpdateConfig(update)),
+                Err(ContractError::InvalidLimit {
+                    name: name.to_string()
+                }
+                .into())
+            );
+        }
+
+        let env = mock_env("admin", &coins(2, "token"));
+        let msg = HandleMsg::UpdateConfig(ConfigUpdate {
+            max_history: Some(10),
+            ..ConfigUpdate::default()
+        });
+        let res = handle(&mut deps, env, msg)?;
+        let answer: ConfigAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            ConfigAnswer::UpdateConfig {
+                config: Config {
+                    admin: HumanAddr::from("admin"),
+                    max_history: 10,
+                    global_stats_enabled: true,
+                    max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+                    max_variables: DEFAULT_MAX_VARIABLES,
+                    privacy_mode: false,
+                    fee_denom: DEFAULT_FEE_DENOM.to_string(),
+                    prices: BTreeMap::new(),
+                    payment_token: None,
+                }
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_opt_out() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetPreferences {
+            record_history: false,
+            log: None,
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Add(BinaryOp(Uint128(1), Uint128(2))).into(),
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            HandleAnswer {
+                result: Uint128(3),
+                calculation_id: None,
+                recorded: false,
+            }
+        );
+
+        // the per-call override wins over the preference
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Mul(BinaryOp(Uint128(2), Uint128(5))).into(),
+            record: Some(true),
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.calculation_id, Some(0));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let LegacyHandleAnswer(result) =
+            unpack_handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(9))));
+        assert_eq!(result, Uint128(3));
+
+        assert_eq!(
+            query_history(&deps, Uint128(10)),
+            QueryAnswer::CalculationHistory {
+                calcs: vec![StoredCalculation {
+                    id: 0,
+                    left_operand: Uint128(2),
+                    right_operand: Some(Uint128(5)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Mul".to_string(),
+                    result: Uint128(10),
+                    block_height: 12345,
+                }],
+                total: Some(Uint128(1)),
+            }
+        );
+
+        // batches and formula invocations take the override too
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Batch {
+            ops: vec![CalcOp::Sqrt(UnaryOp(Uint128(16))).into()],
+            atomic: true,
+            record: Some(true),
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: BatchAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.results[0].calculation_id, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn calculation_by_id() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for calculation in [
+            BinaryOp(Uint128(7), Uint128(3)),
+            BinaryOp(Uint128(8), Uint128(5)),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Sub(calculation).into(),
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Calculation { id: 1 },
+            padding: None,
+        };
+        let answer: QueryAnswer = from_binary(&query(&deps, msg)?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::Calculation {
+                calc: StoredCalculation {
+                    id: 1,
+                    left_operand: Uint128(8),
+                    right_operand: Some(Uint128(5)),
+                    left_reference: None,
+                    right_reference: None,
+                    formula: None,
+                    session: None,
+                    memo: None,
+                    tags: vec![],
+                    effect: None,
+                    undone: false,
+                    operation: "Sub".to_string(),
+                    result: Uint128(3),
+                    block_height: 12345,
+                }
+            }
+        );
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Calculation { id: 2 },
+            padding: None,
+        };
+        assert_eq!(
+            query(&deps, msg),
+            Err(ContractError::CalculationNotFound { id: 2 }.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        // calculations that aren't recorded are counted too
+        let unrecorded = HandleMsg::Calculate {
+            op: CalcOp::Sqrt(UnaryOp(Uint128(16))).into(),
+            record: Some(false),
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        for (height, msg) in [
+            (100, HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2)))),
+            (101, HandleMsg::Add(BinaryOp(Uint128(3), Uint128(4)))),
+            (102, unrecorded),
+        ] {
+            let mut env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            env.block.height = height;
+            handle(&mut deps, env, msg)?;
+        }
+
+        // stats are not affected by deleting history
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(&mut deps, env, HandleMsg::ClearHistory { padding: None })?;
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        let answer: QueryAnswer = from_binary(&query_stats(&deps, &account)?)?;
+        let mut operations = std::collections::BTreeMap::new();
+        operations.insert("Add".to_string(), 2);
+        operations.insert("Sqrt".to_string(), 1);
+        assert_eq!(
+            answer,
+            QueryAnswer::Stats {
+                total: 3,
+                operations,
+                first_block_height: Some(100),
+                last_block_height: Some(102),
+                failed_attempts: 0,
+            }
+        );
+
+        // the test permit only grants access to the history
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Stats {},
+            padding: None,
+        };
+        assert_eq!(
+            query(&deps, msg),
+            Err(ContractError::PermissionDenied {
+                needed: "stats".to_string(),
+                granted: vec![CalculatorPermission::CalculationHistory],
+            }
+            .into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn global_stats() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("admin", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (sender, msg) in [
+            ("alice", HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2)))),
+            ("alice", HandleMsg::Div(BinaryOp(Uint128(9), Uint128(3)))),
+            ("bob", HandleMsg::Add(BinaryOp(Uint128(5), Uint128(6)))),
+        ] {
+            let env = mock_env(sender, &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+
+        // nothing is published before the interval passed
+        let answer: QueryAnswer =
+            from_binary(&query(&deps, QueryMsg::GlobalStats { padding: None })?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::GlobalStats {
+                total: 0,
+                operations: BTreeMap::new(),
+                accounts: 0,
+                block_height: 12345,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            }
+        );
+
+        let mut env = mock_env("alice", &coins(2, "token"));
+        env.block.height += GLOBAL_STATS_INTERVAL;
+        handle(&mut deps, env, HandleMsg::Sqrt(UnaryOp(Uint128(4))))?;
+
+        let answer: QueryAnswer =
+            from_binary(&query(&deps, QueryMsg::GlobalStats { padding: None })?)?;
+        let mut operations = BTreeMap::new();
+        operations.insert("Add".to_string(), 2);
+        operations.insert("Div".to_string(), 1);
+        assert_eq!(
+            answer,
+            QueryAnswer::GlobalStats {
+                total: 3,
+                operations,
+                accounts: 2,
+                block_height: 12345 + GLOBAL_STATS_INTERVAL,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            }
+        );
+
+        let env = mock_env("admin", &coins(2, "token"));
+        let msg = HandleMsg::UpdateConfig(ConfigUpdate {
+            global_stats_enabled: Some(false),
+            ..ConfigUpdate::default()
+        });
+        handle(&mut deps, env, msg)?;
+
+        assert_eq!(
+            query(&deps, QueryMsg::GlobalStats { padding: None }),
+            Err(ContractError::GlobalStatsDisabled.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compute_query() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let msg = QueryMsg::Compute {
+            op: CalcOp::Mul(BinaryOp(Uint128(6), Uint128(7))),
+            padding: None,
+        };
+        let answer: QueryAnswer = from_binary(&query(&deps, msg)?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::Compute {
+                result: Uint128(42)
+            }
+        );
+
+        let msg = QueryMsg::Compute {
+            op: CalcOp::Div(BinaryOp(Uint128(6), Uint128(0))),
+            padding: None,
+        };
+        assert_eq!(query(&deps, msg), Err(ContractError::DivisionByZero.into()));
+
+        // nothing was recorded
+        assert_eq!(history_ids(&deps), (vec![], Some(Uint128::zero())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(
+            &mut deps,
+            env,
+            InitMsg {
+                max_batch_size: Some(3),
+                ..InitMsg::default()
+            },
+        )?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Batch {
+            ops: vec![
+                CalcOp::Add(BinaryOp(Uint128(1), Uint128(2))).into(),
+                CalcOp::Div(BinaryOp(Uint128(1), Uint128(0))).into(),
+                CalcOp::Sqrt(UnaryOp(Uint128(16))).into(),
+            ],
+            atomic: false,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: BatchAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer.results,
+            vec![
+                BatchItemAnswer {
+                    result: Some(Uint128(3)),
+                    calculation_id: Some(0),
+                    error: None,
+                },
+                BatchItemAnswer {
+                    result: None,
+                    calculation_id: None,
+                    error: Some(r#"{"code":12,"msg":"Divisor can't be zero"}"#.to_string()),
+                },
+                BatchItemAnswer {
+                    result: Some(Uint128(4)),
+                    calculation_id: Some(1),
+                    error: None,
+                },
+            ]
+        );
+        assert_eq!(history_ids(&deps), (vec![1, 0], Some(Uint128(2))));
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        let stats = get_account_stats(&deps.storage, &account)?;
+        assert_eq!((stats.total, stats.failed_attempts), (2, 1));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Batch {
+            ops: vec![
+                CalcOp::Add(BinaryOp(Uint128(1), Uint128(2))).into(),
+                CalcOp::Sub(BinaryOp(Uint128(1), Uint128(2))).into(),
+            ],
+            atomic: true,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::BatchOperationFailed {
+                index: 1,
+                error: Box::new(ContractError::Underflow {
+                    op: "Sub".to_string()
+                }),
+            }
+            .into())
+        );
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Batch {
+            ops: vec![CalcOp::Sqrt(UnaryOp(Uint128(1))).into(); 4],
+            atomic: false,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::BatchTooLarge { max: 3 }.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn operand_references() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Add(BinaryOp(Operand::Last {}, Operand::Value(Uint128(1)))),
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::EmptyHistory.into())
+        );
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2)));
+        handle(&mut deps, env, msg)?;
+
+        let mut results = vec![];
+        for op in [
+            CalcOp::Mul(BinaryOp(Operand::Last {}, Operand::Value(Uint128(10)))),
+            CalcOp::Add(BinaryOp(Operand::ById(0), Operand::Nth(1))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op,
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            let res = handle(&mut deps, env, msg)?;
+            let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+            results.push(answer.result);
+        }
+        assert_eq!(results, vec![Uint128(30), Uint128(6)]);
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        assert_eq!(
+            get_calculation(&deps.storage, &account, 2)?,
+            Some(StoredCalculation {
+                id: 2,
+                left_operand: Uint128(3),
+                right_operand: Some(Uint128(3)),
+                left_reference: Some(Operand::ById(0)),
+                right_reference: Some(Operand::Nth(1)),
+                formula: None,
+                session: None,
+                memo: None,
+                tags: vec![],
+                effect: None,
+                undone: false,
+                operation: "Add".to_string(),
+                result: Uint128(6),
+                block_height: 12345,
+            })
+        );
+
+        for (operand, error) in [
+            (
+                Operand::ById(7),
+                ContractError::CalculationNotFound { id: 7 },
+            ),
+            (Operand::Nth(3), ContractError::NoCalculation { back: 3 }),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Sqrt(UnaryOp(operand)),
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn variables() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(
+            &mut deps,
+            env,
+            InitMsg {
+                max_variables: Some(2),
+                ..InitMsg::default()
+            },
+        )?;
+
+        for (msg, expected) in [
+            (
+                HandleMsg::SetVar {
+                    name: "x".to_string(),
+                    value: Uint128(5),
+                    session: None,
+                    padding: None,
+                },
+                VariableAnswer::SetVar {
+                    name: "x".to_string(),
+                    value: Uint128(5),
+                },
+            ),
+            (
+                HandleMsg::IncrVar {
+                    name: "x".to_string(),
+                    amount: Uint128(3),
+                    session: None,
+                    padding: None,
+                },
+                VariableAnswer::IncrVar {
+                    name: "x".to_string(),
+                    value: Uint128(8),
+                },
+            ),
+            (
+                HandleMsg::IncrVar {
+                    name: "y".to_string(),
+                    amount: Uint128(2),
+                    session: None,
+                    padding: None,
+                },
+                VariableAnswer::IncrVar {
+                    name: "y".to_string(),
+                    value: Uint128(2),
+                },
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let res = handle(&mut deps, env, msg)?;
+            let answer: VariableAnswer = from_binary(&res.data.unwrap())?;
+            assert_eq!(answer, expected);
         }
-    }"#;
+
+        for (msg, error) in [
+            (
+                HandleMsg::SetVar {
+                    name: "z".to_string(),
+                    value: Uint128(1),
+                    session: None,
+                    padding: None,
+                },
+                ContractError::TooManyVariables { max: 2 },
+            ),
+            (
+                HandleMsg::DecrVar {
+                    name: "y".to_string(),
+                    amount: Uint128(3),
+                    session: None,
+                    padding: None,
+                },
+                ContractError::Underflow {
+                    op: "DecrVar".to_string(),
+                },
+            ),
+            (
+                HandleMsg::ClearVar {
+                    name: "z".to_string(),
+                    session: None,
+                    padding: None,
+                },
+                ContractError::VariableNotSet {
+                    name: "z".to_string(),
+                },
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Mul(BinaryOp(
+                Operand::Var("x".to_string()),
+                Operand::Var("y".to_string()),
+            )),
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.result, Uint128(16));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::ClearVar {
+            name: "y".to_string(),
+            session: None,
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        let answer: QueryAnswer = from_binary(&query_variables(&deps, &account)?)?;
+        let mut variables = std::collections::BTreeMap::new();
+        variables.insert("x".to_string(), Uint128(8));
+        assert_eq!(answer, QueryAnswer::Variables { variables });
+
+        // the test permit only grants access to the history
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Variables {},
+            padding: None,
+        };
+        assert_eq!(
+            query(&deps, msg),
+            Err(ContractError::PermissionDenied {
+                needed: "variables".to_string(),
+                granted: vec![CalculatorPermission::CalculationHistory],
+            }
+            .into())
+        );
+
+        Ok(())
+    }
 
     #[test]
-    fn add() -> Result<(), StdError> {
+    fn formulas() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
         let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (name, expr) in [
+            ("hypot", "sqrt(a * a + b * b)"),
+            ("mean", "(a + b) / 2"),
+            ("affine", "a + b * 2 - 1"),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::DefineFormula {
+                name: name.to_string(),
+                params: vec!["a".to_string(), "b".to_string()],
+                expr: expr.to_string(),
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+
+        for (expr, pos, reason) in [
+            ("a + c", 4, "unknown parameter `c`"),
+            ("(a + b", 6, "expected `)`"),
+            ("a b", 2, "unexpected `b`"),
+            ("a % b", 2, "unexpected character `%`"),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::DefineFormula {
+                name: "bad".to_string(),
+                params: vec!["a".to_string(), "b".to_string()],
+                expr: expr.to_string(),
+                padding: None,
+            };
+            let error = ContractError::InvalidExpression {
+                pos,
+                reason: reason.to_string(),
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        let mut results = vec![];
+        for (name, args) in [
+            ("hypot", [Uint128(3), Uint128(4)]),
+            ("mean", [Uint128(3), Uint128(8)]),
+            ("affine", [Uint128(1), Uint128(3)]),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Invoke {
+                name: name.to_string(),
+                args: args.to_vec(),
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            let res = handle(&mut deps, env, msg)?;
+            let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+            results.push(answer.result);
+        }
+        assert_eq!(results, vec![Uint128(5), Uint128(5), Uint128(6)]);
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        assert_eq!(
+            get_calculation(&deps.storage, &account, 0)?,
+            Some(StoredCalculation {
+                id: 0,
+                left_operand: Uint128::zero(),
+                right_operand: None,
+                left_reference: None,
+                right_reference: None,
+                formula: Some(FormulaCall {
+                    author: None,
+                    name: "hypot".to_string(),
+                    version: None,
+                    args: vec![Uint128(3), Uint128(4)],
+                }),
+                session: None,
+                memo: None,
+                tags: vec![],
+                effect: None,
+                undone: false,
+                operation: "Invoke".to_string(),
+                result: Uint128(5),
+                block_height: 12345,
+            })
+        );
+
+        for (name, args, error) in [
+            (
+                "mean",
+                vec![Uint128(1)],
+                ContractError::WrongArgumentCount {
+                    name: "mean".to_string(),
+                    expected: 2,
+                    got: 1,
+                },
+            ),
+            (
+                "median",
+                vec![],
+                ContractError::FormulaNotFound {
+                    name: "median".to_string(),
+                },
+            ),
+            (
+                "affine",
+                vec![Uint128(0), Uint128(0)],
+                ContractError::Underflow {
+                    op: "Sub".to_string(),
+                },
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Invoke {
+                name: name.to_string(),
+                args,
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn public_formulas() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("admin", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (version, expr) in [(1, "a * 2"), (2, "a + a")] {
+            let env = mock_env("alice", &coins(2, "token"));
+            let msg = HandleMsg::PublishFormula(PublishFormulaMsg {
+                name: "double".to_string(),
+                version,
+                description: "Doubles a number".to_string(),
+                params: vec!["a".to_string()],
+                expr: expr.to_string(),
+                padding: None,
+            });
+            handle(&mut deps, env, msg)?;
+        }
+
+        // published versions are immutable
+        let env = mock_env("alice", &coins(2, "token"));
+        let msg = HandleMsg::PublishFormula(PublishFormulaMsg {
+            name: "double".to_string(),
+            version: 1,
+            description: "Triples a number".to_string(),
+            params: vec!["a".to_string()],
+            expr: "a * 3".to_string(),
+            padding: None,
+        });
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::FormulaVersionExists {
+                name: "double".to_string(),
+                version: 1,
+            }
+            .into())
+        );
+
+        let env = mock_env("bob", &coins(2, "token"));
+        let msg = HandleMsg::InvokePublic {
+            author: HumanAddr::from("alice"),
+            name: "double".to_string(),
+            version: 1,
+            args: vec![Uint128(21)],
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.result, Uint128(42));
+
+        let calc = get_calculation(&deps.storage, &HumanAddr::from("bob"), 0)?.unwrap();
+        assert_eq!(
+            calc.formula,
+            Some(FormulaCall {
+                author: Some(HumanAddr::from("alice")),
+                name: "double".to_string(),
+                version: Some(1),
+                args: vec![Uint128(21)],
+            })
+        );
+
+        // only the author can deprecate a formula
+        let msg = HandleMsg::DeprecateFormula {
+            name: "double".to_string(),
+            version: 1,
+            deprecated: true,
+            padding: None,
+        };
+        let env = mock_env("bob", &coins(2, "token"));
+        assert_eq!(
+            handle(&mut deps, env, msg.clone()),
+            Err(ContractError::FormulaVersionNotFound {
+                name: "double".to_string(),
+                version: 1,
+            }
+            .into())
+        );
+        let env = mock_env("alice", &coins(2, "token"));
+        handle(&mut deps, env, msg)?;
+
+        let msg = QueryMsg::ListFormulas {
+            page: Some(Uint128(1)),
+            page_size: Uint128(1),
+            padding: None,
+        };
+        let answer: QueryAnswer = from_binary(&query(&deps, msg)?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::ListFormulas {
+                formulas: vec![PublicFormulaInfo {
+                    author: HumanAddr::from("alice"),
+                    name: "double".to_string(),
+                    version: 2,
+                    description: "Doubles a number".to_string(),
+                    deprecated: false,
+                    params: vec!["a".to_string()],
+                    expr: "a + a".to_string(),
+                }],
+                total: 2,
+            }
+        );
+
+        let msg = QueryMsg::ListFormulas {
+            page: None,
+            page_size: Uint128(1),
+            padding: None,
+        };
+        let answer: QueryAnswer = from_binary(&query(&deps, msg)?)?;
+        match answer {
+            QueryAnswer::ListFormulas { formulas, .. } => assert!(formulas[0].deprecated),
+            _ => panic!("unexpected answer"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sheet() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (cell, content) in [
+            ("A1", CellContent::Value(Uint128(2))),
+            ("A2", CellContent::Value(Uint128(3))),
+            ("A3", CellContent::Formula("A1 + A2".to_string())),
+            ("B1", CellContent::Formula("A3 * 2 + A2".to_string())),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::SetCell {
+                cell: cell.to_string(),
+                content,
+                session: None,
+                callback: None,
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+
+        // A2 doesn't change, but both A3 and B1 depend on A1
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetCell {
+            cell: "A1".to_string(),
+            content: CellContent::Value(Uint128(4)),
+            session: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: SheetAnswer = from_binary(&res.data.unwrap())?;
+        let changed: Vec<(String, Uint128)> = match answer {
+            SheetAnswer::SetCell { changed } => changed
+                .into_iter()
+                .map(|cell| (cell.cell, cell.value))
+                .collect(),
+        };
+        assert_eq!(
+            changed,
+            vec![
+                ("A1".to_string(), Uint128(4)),
+                ("A3".to_string(), Uint128(7)),
+                ("B1".to_string(), Uint128(17)),
+            ]
+        );
+        // one entry per changed cell
+        assert_eq!(history_ids(&deps).1, Some(Uint128(7)));
+
+        for (cell, expr, error) in [
+            (
+                "A1",
+                "B1 - 1",
+                ContractError::CyclicReference {
+                    cell: "A1".to_string(),
+                },
+            ),
+            (
+                "A1",
+                "a2",
+                ContractError::InvalidCellName {
+                    name: "a2".to_string(),
+                },
+            ),
+            (
+                "A4",
+                "A2 - B1",
+                ContractError::CellFailed {
+                    cell: "A4".to_string(),
+                    error: Box::new(ContractError::Underflow {
+                        op: "Sub".to_string(),
+                    }),
+                },
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::SetCell {
+                cell: cell.to_string(),
+                content: CellContent::Formula(expr.to_string()),
+                session: None,
+                callback: None,
+                padding: None,
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        let answer: QueryAnswer = from_binary(&query_sheet(&deps, &account)?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::Sheet {
+                cells: vec![
+                    CellInfo {
+                        cell: "A1".to_string(),
+                        formula: None,
+                        value: Uint128(4),
+                    },
+                    CellInfo {
+                        cell: "A2".to_string(),
+                        formula: None,
+                        value: Uint128(3),
+                    },
+                    CellInfo {
+                        cell: "A3".to_string(),
+                        formula: Some("A1 + A2".to_string()),
+                        value: Uint128(7),
+                    },
+                    CellInfo {
+                        cell: "B1".to_string(),
+                        formula: Some("A3 * 2 + A2".to_string()),
+                        value: Uint128(17),
+                    },
+                ]
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sessions() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (label, expected) in [("taxes", 0), ("groceries", 1)] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::StartSession {
+                label: label.to_string(),
+                padding: None,
+            };
+            let res = handle(&mut deps, env, msg)?;
+            let answer: SessionAnswer = from_binary(&res.data.unwrap())?;
+            assert_eq!(answer, SessionAnswer::StartSession { id: expected });
+        }
+
+        for (session, left) in [(Some(0), 1), (Some(1), 2), (None, 3), (Some(0), 4)] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Add(BinaryOp(Uint128(left), Uint128(10))).into(),
+                record: None,
+                session,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+        for msg in [
+            HandleMsg::Accumulate {
+                op: AccumulatorOp::Add,
+                operand: Operand::Value(Uint128(5)),
+                session: Some(1),
+                callback: None,
+                padding: None,
+            },
+            HandleMsg::SetCell {
+                cell: "A1".to_string(),
+                content: CellContent::Value(Uint128(6)),
+                session: Some(1),
+                callback: None,
+                padding: None,
+            },
+            HandleMsg::SetVar {
+                name: "x".to_string(),
+                value: Uint128(7),
+                session: Some(1),
+                padding: None,
+            },
+            HandleMsg::ResetAccumulator {
+                session: Some(1),
+                padding: None,
+            },
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(
+            &mut deps,
+            env,
+            HandleMsg::EndSession {
+                id: 0,
+                padding: None,
+            },
+        )?;
+
+        for (session, error) in [
+            (0, ContractError::SessionEnded { id: 0 }),
+            (5, ContractError::SessionNotFound { id: 5 }),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Sqrt(UnaryOp(Uint128(4))).into(),
+                record: None,
+                session: Some(session),
+                memo: None,
+                tags: None,
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::IncrVar {
+            name: "x".to_string(),
+            amount: Uint128(1),
+            session: Some(0),
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::SessionEnded { id: 0 }.into())
+        );
 
-        // initial calculation history for an account should be unexistent
         let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Session {
+                id: 0,
                 page: None,
-                page_size: Uint128(3),
+                page_size: Uint128(10),
             },
+            padding: None,
         };
+        let answer: QueryAnswer = from_binary(&query(&deps, msg)?)?;
+        match answer {
+            QueryAnswer::Session { session, calcs } => {
+                assert_eq!(
+                    session,
+                    Session {
+                        id: 0,
+                        label: "taxes".to_string(),
+                        start_block_height: 12345,
+                        end_block_height: Some(12345),
+                    }
+                );
+                let calcs: Vec<(u64, Uint128)> =
+                    calcs.iter().map(|calc| (calc.id, calc.result)).collect();
+                assert_eq!(calcs, vec![(0, Uint128(11)), (3, Uint128(14))]);
+            }
+            _ => panic!("unexpected answer"),
+        }
 
-        let raw_res = query(&mut deps, msg)?;
+        let session_ids = |deps: &Extern<_, _, _>, id| -> Vec<u64> {
+            let msg = QueryMsg::WithPermit {
+                permit: serde_json::from_str(PERMIT).unwrap(),
+                query: QueryWithPermit::Session {
+                    id,
+                    page: None,
+                    page_size: Uint128(10),
+                },
+                padding: None,
+            };
+            match from_binary(&query(deps, msg).unwrap()).unwrap() {
+                QueryAnswer::Session { calcs, .. } => calcs.iter().map(|calc| calc.id).collect(),
+                _ => panic!("unexpected answer"),
+            }
+        };
+        assert_eq!(session_ids(&deps, 1), vec![1, 4, 5, 6, 7]);
 
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        // calculations that leave the history leave their session too
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::DeleteCalculation {
+            id: 0,
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+        assert_eq!(session_ids(&deps, 0), vec![3]);
+        let positions = ReadonlyPrefixedStorage::multilevel(
+            &[
+                PREFIX_SESSION_POSITIONS,
+                b"qcYLPHTmmt6mhJpcp3UN",
+                &0u64.to_be_bytes(),
+            ],
+            &deps.storage,
+        );
+        assert_eq!(positions.get(&0u64.to_be_bytes()), None);
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(&mut deps, env, HandleMsg::ClearHistory { padding: None })?;
+        for id in [0u64, 1] {
+            let store = ReadonlyPrefixedStorage::multilevel(
+                &[
+                    PREFIX_SESSION_CALCULATIONS,
+                    b"qcYLPHTmmt6mhJpcp3UN",
+                    &id.to_be_bytes(),
+                ],
+                &deps.storage,
+            );
+            let store = AppendStore::<Option<u64>, _, _>::attach(&store).unwrap()?;
+            assert_eq!(store.len(), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tags() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        for (memo, tags) in [
+            (Some("Q3 payout split"), vec!["payout"]),
+            (None, vec!["payout", "tax", "payout"]),
+            (None, vec![]),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Div(BinaryOp(Uint128(90), Uint128(3))).into(),
+                record: None,
+                session: None,
+                memo: memo.map(String::from),
+                tags: Some(tags.into_iter().map(String::from).collect()),
+                log: None,
+                callback: None,
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+
+        let by_tag = |deps: &Extern<_, _, _>, tag: &str, cursor| -> (Vec<u64>, Option<u64>) {
+            let msg = QueryMsg::WithPermit {
+                permit: serde_json::from_str(PERMIT).unwrap(),
+                query: QueryWithPermit::ByTag {
+                    tag: tag.to_string(),
+                    cursor,
+                    limit: 1,
+                },
+                padding: None,
+            };
+            match from_binary(&query(deps, msg).unwrap()).unwrap() {
+                QueryAnswer::ByTag { calcs, next_cursor } => {
+                    (calcs.iter().map(|calc| calc.id).collect(), next_cursor)
+                }
+                _ => panic!("unexpected answer"),
+            }
+        };
+        assert_eq!(by_tag(&deps, "payout", None), (vec![1], Some(1)));
+        assert_eq!(by_tag(&deps, "payout", Some(1)), (vec![0], None));
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::TagCalculation {
+            id: 0,
+            tags: vec!["tax".to_string()],
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
+
+        // the cursor is returned as long as there are older entries, even if they were untagged
+        assert_eq!(by_tag(&deps, "payout", None), (vec![1], Some(1)));
+        assert_eq!(by_tag(&deps, "payout", Some(1)), (vec![], None));
+        assert_eq!(by_tag(&deps, "tax", None), (vec![0], Some(1)));
+        assert_eq!(by_tag(&deps, "tax", Some(1)), (vec![1], None));
+
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+        let calc = get_calculation(&deps.storage, &account, 0)?.unwrap();
+        assert_eq!(calc.memo, Some("Q3 payout split".to_string()));
+        assert_eq!(calc.tags, vec!["tax".to_string()]);
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::TagCalculation {
+            id: 0,
+            tags: (0..9).map(|i| i.to_string()).collect(),
+            padding: None,
+        };
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![],
-                total: Some(Uint128::zero()),
+            handle(&mut deps, env, msg),
+            Err(ContractError::TooManyTags { max: 8 }.into())
+        );
+
+        // clearing the history leaves nothing behind in the tag lists
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(&mut deps, env, HandleMsg::ClearHistory { padding: None })?;
+        for tag in ["payout", "tax"] {
+            let store = ReadonlyPrefixedStorage::multilevel(
+                &[PREFIX_TAGS, b"qcYLPHTmmt6mhJpcp3UN", tag.as_bytes()],
+                &deps.storage,
+            );
+            let store = AppendStore::<Option<u64>, _, _>::attach(&store).unwrap()?;
+            assert_eq!(store.len(), 0);
+
+            let positions = ReadonlyPrefixedStorage::multilevel(
+                &[
+                    PREFIX_TAG_POSITIONS,
+                    b"qcYLPHTmmt6mhJpcp3UN",
+                    tag.as_bytes(),
+                ],
+                &deps.storage,
+            );
+            for id in 0..3u64 {
+                assert_eq!(positions.get(&id.to_be_bytes()), None);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn undo_redo() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+
+        for msg in [
+            HandleMsg::SetVar {
+                name: "x".to_string(),
+                value: Uint128(5),
+                session: None,
+                padding: None,
+            },
+            HandleMsg::IncrVar {
+                name: "x".to_string(),
+                amount: Uint128(3),
+                session: None,
+                padding: None,
+            },
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+
+        let undo_redo = |deps: &mut Extern<_, _, _>, msg: HandleMsg| -> StdResult<HistoryAnswer> {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            from_binary(&handle(deps, env, msg)?.data.unwrap())
+        };
+        for (msg, id) in [
+            (HandleMsg::Undo { padding: None }, 2),
+            (HandleMsg::Undo { padding: None }, 1),
+            (HandleMsg::Redo { padding: None }, 1),
+            (HandleMsg::Undo { padding: None }, 1),
+            (HandleMsg::Undo { padding: None }, 0),
+        ] {
+            let expected = match msg {
+                HandleMsg::Undo { padding: None } => HistoryAnswer::Undo { id },
+                _ => HistoryAnswer::Redo { id },
+            };
+            assert_eq!(undo_redo(&mut deps, msg)?, expected);
+        }
+        assert_eq!(
+            undo_redo(&mut deps, HandleMsg::Undo { padding: None }),
+            Err(ContractError::NothingToUndo.into())
+        );
+        assert!(get_variables(&deps.storage, &account)?.is_empty());
+
+        assert_eq!(
+            undo_redo(&mut deps, HandleMsg::Redo { padding: None })?,
+            HistoryAnswer::Redo { id: 0 }
+        );
+        assert_eq!(
+            get_variables(&deps.storage, &account)?.get("x"),
+            Some(&Uint128(5))
+        );
+        // undone calculations stay in the history
+        let undone: Vec<bool> = match query_history(&deps, Uint128(10)) {
+            QueryAnswer::CalculationHistory { calcs, .. } => {
+                calcs.iter().map(|calc| calc.undone).collect()
+            }
+            other => panic!("unexpected answer {:?}", other),
+        };
+        assert_eq!(undone, vec![true, true, false]);
+
+        // references skip the undone calculations
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Accumulate {
+            op: AccumulatorOp::Add,
+            operand: Operand::Nth(1),
+            session: None,
+            callback: None,
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::NoCalculation { back: 1 }.into())
+        );
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Accumulate {
+            op: AccumulatorOp::Add,
+            operand: Operand::Last {},
+            session: None,
+            callback: None,
+            padding: None,
+        };
+        let answer: AccumulatorAnswer = from_binary(&handle(&mut deps, env, msg)?.data.unwrap())?;
+        assert_eq!(
+            answer,
+            AccumulatorAnswer::Accumulate {
+                previous: Uint128::zero(),
+                value: Uint128(5),
+            }
+        );
+
+        // setting a cell is undone as a whole, recomputing the cells that depend on it
+        for (cell, content) in [
+            ("A1", CellContent::Value(Uint128(2))),
+            ("A2", CellContent::Formula("A1 * 2".to_string())),
+            ("A1", CellContent::Value(Uint128(3))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::SetCell {
+                cell: cell.to_string(),
+                content,
+                session: None,
+                callback: None,
+                padding: None,
+            };
+            handle(&mut deps, env, msg)?;
+        }
+        // new calculations discard what was undone
+        assert_eq!(
+            undo_redo(&mut deps, HandleMsg::Redo { padding: None }),
+            Err(ContractError::NothingToRedo.into())
+        );
+        undo_redo(&mut deps, HandleMsg::Undo { padding: None })?;
+        let sheet = get_sheet(&deps.storage, &account)?;
+        assert_eq!(sheet["A1"].value, Uint128(2));
+        assert_eq!(sheet["A2"].value, Uint128(4));
+        let undone: Vec<bool> = match query_history(&deps, Uint128(3)) {
+            QueryAnswer::CalculationHistory { calcs, .. } => {
+                calcs.iter().map(|calc| calc.undone).collect()
+            }
+            other => panic!("unexpected answer {:?}", other),
+        };
+        assert_eq!(undone, vec![true, true, false]);
+
+        undo_redo(&mut deps, HandleMsg::Redo { padding: None })?;
+        let sheet = get_sheet(&deps.storage, &account)?;
+        assert_eq!(sheet["A1"].value, Uint128(3));
+        assert_eq!(sheet["A2"].value, Uint128(6));
+        undo_redo(&mut deps, HandleMsg::Undo { padding: None })?;
+
+        // changes are recorded even if the history isn't, so they can still be undone
+        for msg in [
+            HandleMsg::SetPreferences {
+                record_history: false,
+                log: None,
+                padding: None,
+            },
+            HandleMsg::SetVar {
+                name: "y".to_string(),
+                value: Uint128(7),
+                session: None,
+                padding: None,
+            },
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            handle(&mut deps, env, msg)?;
+        }
+        undo_redo(&mut deps, HandleMsg::Undo { padding: None })?;
+        assert_eq!(get_variables(&deps.storage, &account)?.get("y"), None);
+
+        // and calculations that aren't recorded still discard what was undone
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(
+            &mut deps,
+            env,
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+        )?;
+        assert_eq!(
+            undo_redo(&mut deps, HandleMsg::Redo { padding: None }),
+            Err(ContractError::NothingToRedo.into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accumulator() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+        let account = HumanAddr::from("qcYLPHTmmt6mhJpcp3UN");
+
+        for (op, operand, previous, value) in [
+            (AccumulatorOp::Add, Operand::Value(Uint128(10)), 0, 10),
+            (AccumulatorOp::Mul, Operand::Value(Uint128(3)), 10, 30),
+            (AccumulatorOp::Sub, Operand::Last {}, 30, 0),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let res = handle(
+                &mut deps,
+                env,
+                HandleMsg::Accumulate {
+                    op,
+                    operand,
+                    session: None,
+                    callback: None,
+                    padding: None,
+                },
+            )?;
+            let answer: AccumulatorAnswer = from_binary(&res.data.unwrap())?;
+            assert_eq!(
+                answer,
+                AccumulatorAnswer::Accumulate {
+                    previous: Uint128(previous),
+                    value: Uint128(value),
+                }
+            );
+        }
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Accumulate {
+            op: AccumulatorOp::Sub,
+            operand: Operand::Value(Uint128(1)),
+            session: None,
+            callback: None,
+            padding: None,
+        };
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::Underflow {
+                op: "Sub".to_string()
+            }
+            .into())
+        );
+
+        // the history has the accumulator before and after each update
+        let calc = get_calculation(&deps.storage, &account, 2)?.unwrap();
+        assert_eq!(
+            (
+                calc.left_operand,
+                calc.right_operand,
+                calc.right_reference,
+                calc.operation.as_str(),
+                calc.result,
+            ),
+            (
+                Uint128(30),
+                Some(Uint128(30)),
+                Some(Operand::Last {}),
+                "Sub",
+                Uint128::zero(),
+            )
+        );
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        handle(&mut deps, env, HandleMsg::Undo { padding: None })?;
+        let answer: QueryAnswer = from_binary(&query_accumulator(&deps, &account)?)?;
+        assert_eq!(answer, QueryAnswer::Accumulator { value: Uint128(30) });
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let res = handle(
+            &mut deps,
+            env,
+            HandleMsg::ResetAccumulator {
+                session: None,
+                padding: None,
+            },
+        )?;
+        let answer: AccumulatorAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            AccumulatorAnswer::ResetAccumulator {
+                previous: Uint128(30)
+            }
+        );
+        assert_eq!(get_accumulator(&deps.storage, &account)?, Uint128::zero());
+
+        let msg = QueryMsg::WithPermit {
+            permit: serde_json::from_str(PERMIT).unwrap(),
+            query: QueryWithPermit::Accumulator {},
+            padding: None,
+        };
+        assert_eq!(
+            query(&deps, msg),
+            Err(ContractError::PermissionDenied {
+                needed: "accumulator".to_string(),
+                granted: vec![CalculatorPermission::CalculationHistory],
             }
+            .into())
         );
 
-        let msg = HandleMsg::Add(BinaryOp(Uint128(12), Uint128(30)));
+        Ok(())
+    }
+    #[test]
+    fn response_padding() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
+
+        // the smallest and largest results can't be told apart by their length
+        let mut lengths = vec![];
+        for (left, right) in [(0, 0), (u128::MAX, 0)] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            let msg = HandleMsg::Add(BinaryOp(Uint128(left), Uint128(right)));
+            let data = handle(&mut deps, env, msg)?.data.unwrap();
+            lengths.push(data.len());
+
+            let answer: LegacyHandleAnswer = from_binary(&data)?;
+            assert_eq!(answer, LegacyHandleAnswer(Uint128(left + right)));
+        }
+        assert_eq!(lengths, vec![BLOCK_SIZE, BLOCK_SIZE]);
 
-        // it must be this key since that is who signed the previous query
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let HandleAnswer(result) = unpack_handle(&mut deps, env, msg);
-        assert_eq!(result, Uint128(42));
+        // padding is deterministic, so the same query always has the same response
+        let msg: QueryMsg = serde_json::from_str(&format!(
+            r#"{{"with_permit":{{"permit":{},"query":{{"calculation_history":{{"page_size":"10"}}}},"padding":"ignored"}}}}"#,
+            PERMIT
+        ))
+        .unwrap();
+        let res = query(&deps, msg.clone())?;
+        assert_eq!(res.len() % BLOCK_SIZE, 0);
+        assert!(res.len() > BLOCK_SIZE);
+        assert_eq!(query(&deps, msg)?, res);
+        assert!(res.as_slice().ends_with(b" "));
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(3),
-            },
+        Ok(())
+    }
+    #[test]
+    fn privacy_mode() -> Result<(), StdError> {
+        let mut deps = my_mock_dependencies(&coins(2, "token"));
+        let env = mock_env("admin", &coins(2, "token"));
+        let msg = InitMsg {
+            max_variables: Some(1),
+            global_stats_enabled: Some(false),
+            privacy_mode: Some(true),
+            ..InitMsg::default()
         };
+        init(&mut deps, env, msg)?;
 
-        let raw_res = query(&mut deps, msg)?;
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        for msg in [
+            r#"{"set_var":{"name":"x","value":"1"}}"#,
+            r#"{"start_session":{"label":"closed"}}"#,
+            r#"{"end_session":{"id":0}}"#,
+            r#"{"define_formula":{"name":"f","params":["a"],"expr":"a"}}"#,
+        ] {
+            let env = mock_env("alice", &coins(2, "token"));
+            handle(&mut deps, env, serde_json::from_str(msg).unwrap())?;
+        }
+
+        let hidden = |kind: PrivateError| {
+            let description = match kind {
+                PrivateError::InvalidRequest => "invalid request",
+                PrivateError::NotFound => "not found",
+                PrivateError::Arithmetic => "arithmetic error",
+                PrivateError::Unauthorized => "unauthorized",
+                PrivateError::Internal => "internal error",
+            };
+            let msg = format!("Error {}: {}", kind.code(), description);
+            StdError::generic_err(format!("{:<32}", msg))
+        };
+
+        use PrivateError::*;
+        let long = |len: usize| "a".repeat(len);
+        let sub = r#"{"sub":[{"value":"1"},{"value":"2"}]}"#;
+        let add = r#"{"add":[{"value":"1"},{"value":"2"}]}"#;
+        for (sender, msg, kind) in [
+            (
+                "bob",
+                r#"{"calculate":{"op":{"sqrt":{"last":{}}}}}"#.to_string(),
+                NotFound,
+            ),
+            ("bob", r#"{"undo":{}}"#.to_string(), NotFound),
+            ("bob", r#"{"redo":{}}"#.to_string(), NotFound),
+            (
+                "alice",
+                r#"{"calculate":{"op":{"sqrt":{"nth":100}}}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"calculate":{"op":{"sqrt":{"by_id":100}}}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"calculate":{"op":{"sqrt":{"var":"y"}}}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                format!(r#"{{"calculate":{{"op":{}}}}}"#, sub),
+                Arithmetic,
+            ),
+            ("alice", r#"{"div":["1","0"]}"#.to_string(), Arithmetic),
+            (
+                "alice",
+                r#"{"mul":["340282366920938463463374607431768211455","2"]}"#.to_string(),
+                Arithmetic,
+            ),
+            (
+                "alice",
+                format!(r#"{{"calculate":{{"op":{},"memo":"{}"}}}}"#, sub, long(129)),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(r#"{{"calculate":{{"op":{},"tags":[""]}}}}"#, sub),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(
+                    r#"{{"calculate":{{"op":{},"tags":["{}"]}}}}"#,
+                    sub,
+                    long(33)
+                ),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(
+                    r#"{{"calculate":{{"op":{},"tags":{:?}}}}}"#,
+                    sub,
+                    ["1", "2", "3", "4", "5", "6", "7", "8", "9"]
+                ),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(r#"{{"calculate":{{"op":{},"session":5}}}}"#, add),
+                NotFound,
+            ),
+            (
+                "alice",
+                format!(r#"{{"calculate":{{"op":{},"session":0}}}}"#, add),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"batch":{"ops":[],"atomic":true}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(
+                    r#"{{"batch":{{"ops":[{}],"atomic":true}}}}"#,
+                    vec![sub; 17].join(",")
+                ),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(r#"{{"batch":{{"ops":[{}],"atomic":true}}}}"#, sub),
+                Arithmetic,
+            ),
+            (
+                "alice",
+                format!(r#"{{"start_session":{{"label":"{}"}}}}"#, long(65)),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"tag_calculation":{"id":100,"tags":[]}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"delete_calculation":{"id":100}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"set_retention":{"max_entries":0}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"set_var":{"name":"","value":"1"}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"set_var":{"name":"y","value":"1"}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"incr_var":{"name":"x","amount":"340282366920938463463374607431768211455"}}"#
+                    .to_string(),
+                Arithmetic,
+            ),
+            (
+                "alice",
+                r#"{"decr_var":{"name":"x","amount":"2"}}"#.to_string(),
+                Arithmetic,
+            ),
+            (
+                "alice",
+                r#"{"clear_var":{"name":"y"}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"accumulate":{"op":"div","operand":{"value":"0"}}}"#.to_string(),
+                Arithmetic,
+            ),
+            (
+                "alice",
+                r#"{"define_formula":{"name":"","params":[],"expr":"1"}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"define_formula":{"name":"g","params":["a"],"expr":"a +"}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"invoke":{"name":"g","args":[]}}"#.to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"invoke":{"name":"f","args":[]}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                format!(
+                    r#"{{"publish_formula":{{"name":"f","version":1,"description":"{}","params":[],"expr":"1"}}}}"#,
+                    long(257)
+                ),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"invoke_public":{"author":"alice","name":"f","version":1,"args":[]}}"#
+                    .to_string(),
+                NotFound,
+            ),
+            (
+                "alice",
+                r#"{"set_cell":{"cell":"a1","content":{"value":"1"}}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"set_cell":{"cell":"A1","content":{"formula":"A1 + 1"}}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "alice",
+                r#"{"update_config":{"max_history":10}}"#.to_string(),
+                Unauthorized,
+            ),
+            (
+                "admin",
+                r#"{"update_config":{"max_history":0}}"#.to_string(),
+                InvalidRequest,
+            ),
+            (
+                "admin",
+                r#"{"update_config":{"max_batch_size":0}}"#.to_string(),
+                InvalidRequest,
+            ),
+        ] {
+            let env = mock_env(sender, &coins(2, "token"));
+            let msg: HandleMsg = serde_json::from_str(&msg).unwrap();
+            assert_eq!(handle(&mut deps, env, msg), Err(hidden(kind)), "{}", sender);
+        }
+
+        // errors of a batch that isn't atomic are part of its answer
+        let env = mock_env("alice", &coins(2, "token"));
+        let msg = format!(r#"{{"batch":{{"ops":[{}],"atomic":false}}}}"#, sub);
+        let res = handle(&mut deps, env, serde_json::from_str(&msg).unwrap())?;
+        let answer: BatchAnswer = from_binary(&res.data.unwrap())?;
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![StoredCalculation {
-                    left_operand: Uint128(12),
-                    right_operand: Some(Uint128(30)),
-                    operation: "Add".to_string(),
-                    result: Uint128(42)
-                }],
-                total: Some(Uint128(1)),
-            }
+            answer.results[0].error,
+            Some(error_message(hidden(Arithmetic)))
         );
 
+        let permit_query = |permit: &str, query: &str| {
+            format!(
+                r#"{{"with_permit":{{"permit":{},"query":{}}}}}"#,
+                permit, query
+            )
+        };
+        let other_contract = PERMIT.replace("cosmos2contract", "other");
+        for (msg, kind) in [
+            (r#"{"global_stats":{}}"#.to_string(), Unauthorized),
+            (
+                r#"{"compute":{"op":{"add":["340282366920938463463374607431768211455","1"]}}}"#
+                    .to_string(),
+                Arithmetic,
+            ),
+            (permit_query(PERMIT, r#"{"stats":{}}"#), Unauthorized),
+            (
+                permit_query(&other_contract, r#"{"calculation":{"id":0}}"#),
+                Unauthorized,
+            ),
+            (
+                permit_query(PERMIT, r#"{"calculation":{"id":100}}"#),
+                NotFound,
+            ),
+            (
+                permit_query(PERMIT, r#"{"session":{"id":5,"page_size":"1"}}"#),
+                NotFound,
+            ),
+        ] {
+            let msg: QueryMsg = serde_json::from_str(&msg).unwrap();
+            assert_eq!(query(&deps, msg), Err(hidden(kind)));
+        }
+
         Ok(())
     }
 
     #[test]
-    fn sub_underflow() -> Result<(), StdError> {
+    fn error_codes() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        let env = mock_env("admin", &coins(2, "token"));
+        init(&mut deps, env, InitMsg::default())?;
 
-        let msg = HandleMsg::Sub(BinaryOp(Uint128(23), Uint128(113)));
+        for (msg, payload) in [
+            (
+                r#"{"calculate":{"op":{"div":[{"value":"1"},{"value":"0"}]}}}"#,
+                r#"{"code":12,"msg":"Divisor can't be zero"}"#,
+            ),
+            (
+                r#"{"update_config":{"max_history":10}}"#,
+                r#"{"code":20,"msg":"This is an admin command and can only be run from the admin address"}"#,
+            ),
+            (r#"{"undo":{}}"#, r#"{"code":37,"msg":"Nothing to undo"}"#),
+            (
+                r#"{"define_formula":{"name":"f","params":["a"],"expr":"a +"}}"#,
+                r#"{"code":56,"msg":"Invalid formula: unexpected end of expression at position 3"}"#,
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+            assert_eq!(
+                handle(&mut deps, env, serde_json::from_str(msg).unwrap()),
+                Err(StdError::generic_err(payload))
+            );
+        }
 
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let res = handle(&mut deps, env, msg);
+        // errors of the std library have a code too
+        let err = StdError::not_found("Config");
+        let payload = format!(
+            r#"{{"code":1,"msg":{}}}"#,
+            serde_json::to_string(&err.to_string()).unwrap()
+        );
         assert_eq!(
-            res,
-            Err(StdError::generic_err("Underflow in Sub operation"))
+            StdError::from(ContractError::from(err)),
+            StdError::generic_err(payload)
         );
 
         Ok(())
     }
 
     #[test]
-    fn sub() -> Result<(), StdError> {
+    fn log_attributes() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
         let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        init(&mut deps, env, InitMsg::default())?;
 
-        let msg = HandleMsg::Sub(BinaryOp(Uint128(123), Uint128(13)));
+        // only the action, encrypted, by default
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2)));
+        let res = handle(&mut deps, env, msg)?;
+        assert_eq!(res.log, vec![log("action", "add")]);
 
-        // it must be this key since that is who signed the query
         let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let HandleAnswer(result) = unpack_handle(&mut deps, env, msg);
-        assert_eq!(result, Uint128(110));
+        let msg = HandleMsg::SetPreferences {
+            record_history: true,
+            log: Some(LogSettings {
+                detail: LogDetail::Calculation,
+                plaintext: false,
+            }),
+            padding: None,
+        };
+        handle(&mut deps, env, msg)?;
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(3),
-            },
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Sqrt(UnaryOp(Uint128(16)));
+        let res = handle(&mut deps, env, msg)?;
+        assert_eq!(
+            res.log,
+            vec![
+                log("action", "sqrt"),
+                log("operation", "Sqrt"),
+                log("calculation_id", "1"),
+            ]
+        );
+
+        // the settings of a call override the preferences
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Sub(BinaryOp(Operand::Last {}, Operand::Value(Uint128(1)))),
+            record: Some(false),
+            session: None,
+            memo: None,
+            tags: None,
+            log: Some(LogSettings {
+                detail: LogDetail::Full,
+                plaintext: true,
+            }),
+            callback: None,
+            padding: None,
         };
+        let res = handle(&mut deps, env, msg)?;
+        assert_eq!(
+            res.log,
+            vec![
+                plaintext_log("action", "calculate"),
+                plaintext_log("operation", "Sub"),
+                plaintext_log("left_operand", "4"),
+                plaintext_log("right_operand", "1"),
+                plaintext_log("result", "3"),
+            ]
+        );
 
-        let raw_res = query(&mut deps, msg)?;
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        // the log settings are kept when only `record_history` is set
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetPreferences {
+            record_history: false,
+            log: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let answer: ConfigAnswer = from_binary(&res.data.unwrap())?;
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![StoredCalculation {
-                    left_operand: Uint128(123),
-                    right_operand: Some(Uint128(13)),
-                    operation: "Sub".to_string(),
-                    result: Uint128(110)
-                }],
-                total: Some(Uint128(1)),
+            answer,
+            ConfigAnswer::SetPreferences {
+                record_history: false,
+                log: LogSettings {
+                    detail: LogDetail::Calculation,
+                    plaintext: false,
+                },
             }
         );
+        assert_eq!(res.log, vec![log("action", "set_preferences")]);
+
+        // updates of variables, cells and the accumulator are logged like other calculations
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let msg = HandleMsg::SetVar {
+            name: "x".to_string(),
+            value: Uint128(5),
+            session: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        assert_eq!(
+            res.log,
+            vec![
+                log("action", "set_var"),
+                log("operation", "SetVar"),
+                log("calculation_id", "2"),
+            ]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn mul() -> Result<(), StdError> {
+    fn callbacks() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
         let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
-
-        let msg = HandleMsg::Mul(BinaryOp(Uint128(23), Uint128(50)));
+        init(&mut deps, env, InitMsg::default())?;
 
-        // it must be this key since that is who signed the query
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let HandleAnswer(result) = unpack_handle(&mut deps, env, msg);
-        assert_eq!(result, Uint128(1150));
+        let code_hash = "ab".repeat(32);
+        let callback = |msg_template: &str| Callback {
+            contract_addr: HumanAddr::from("receiver"),
+            code_hash: code_hash.clone(),
+            msg_template: msg_template.to_string(),
+        };
+        let execute = |msg: &str| -> CosmosMsg {
+            WasmMsg::Execute {
+                contract_addr: HumanAddr::from("receiver"),
+                callback_code_hash: code_hash.clone(),
+                msg: Binary(msg.as_bytes().to_vec()),
+                send: vec![],
+            }
+            .into()
+        };
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(3),
-            },
+        // recorded even though the call asks not to
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::Calculate {
+            op: CalcOp::Add(BinaryOp(
+                Operand::Value(Uint128(1)),
+                Operand::Value(Uint128(2)),
+            )),
+            record: Some(false),
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: Some(callback(r#"{"deposit":{"amount":"{result}"}}"#)),
+            padding: None,
         };
+        let res = handle(&mut deps, env, msg)?;
+        assert_eq!(res.messages, vec![execute(r#"{"deposit":{"amount":"3"}}"#)]);
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.calculation_id, Some(0));
 
-        let raw_res = query(&mut deps, msg)?;
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        // one message per successful operation of a batch
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::Batch {
+            ops: vec![
+                CalcOp::Sqrt(UnaryOp(Uint128(9))).into(),
+                CalcOp::Div(BinaryOp(Uint128(1), Uint128(0))).into(),
+                CalcOp::Mul(BinaryOp(Uint128(2), Uint128(5))).into(),
+            ],
+            atomic: false,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: Some(callback(r#"{"result":"{result}"}"#)),
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![StoredCalculation {
-                    left_operand: Uint128(23),
-                    right_operand: Some(Uint128(50)),
-                    operation: "Mul".to_string(),
-                    result: Uint128(1150)
-                }],
-                total: Some(Uint128(1)),
-            }
+            res.messages,
+            vec![execute(r#"{"result":"3"}"#), execute(r#"{"result":"10"}"#)]
         );
 
+        // the new value of the accumulator or of the set cell
+        for (msg, expected) in [
+            (
+                HandleMsg::Accumulate {
+                    op: AccumulatorOp::Add,
+                    operand: Operand::Value(Uint128(7)),
+                    session: None,
+                    callback: Some(callback(r#"{"result":"{result}"}"#)),
+                    padding: None,
+                },
+                r#"{"result":"7"}"#,
+            ),
+            (
+                HandleMsg::SetCell {
+                    cell: "A1".to_string(),
+                    content: CellContent::Formula("2 * 4".to_string()),
+                    session: None,
+                    callback: Some(callback(r#"{"result":"{result}"}"#)),
+                    padding: None,
+                },
+                r#"{"result":"8"}"#,
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            let res = handle(&mut deps, env, msg)?;
+            assert_eq!(res.messages, vec![execute(expected)]);
+        }
+
+        let invalid = |reason: &str| ContractError::InvalidCallback {
+            reason: reason.to_string(),
+        };
+        for (callback, error) in [
+            (
+                Callback {
+                    contract_addr: HumanAddr::from("ab"),
+                    ..callback("{result}")
+                },
+                invalid("bad contract address"),
+            ),
+            (
+                Callback {
+                    contract_addr: HumanAddr::from("cosmos2contract"),
+                    ..callback("{result}")
+                },
+                invalid("the calculator can't call itself back"),
+            ),
+            (
+                Callback {
+                    code_hash: "xyz".to_string(),
+                    ..callback("{result}")
+                },
+                invalid("code hashes are 64 hexadecimal digits"),
+            ),
+            (
+                callback(r#"{"result":"3"}"#),
+                invalid("the message template must contain `{result}`"),
+            ),
+            (
+                callback(&"{result}".repeat(200)),
+                ContractError::CallbackTooLong {
+                    max: MAX_CALLBACK_MSG_LENGTH,
+                },
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Sqrt(UnaryOp(Operand::Value(Uint128(4)))),
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: Some(callback),
+                padding: None,
+            };
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
         Ok(())
     }
 
     #[test]
-    fn div() -> Result<(), StdError> {
+    fn hooks() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
         let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        init(&mut deps, env, InitMsg::default())?;
 
-        let msg = HandleMsg::Div(BinaryOp(Uint128(23), Uint128(50)));
+        let code_hash = "cd".repeat(32);
+        let register = |contract_addr: &str, code_hash: &str| HandleMsg::RegisterHook {
+            contract_addr: HumanAddr::from(contract_addr),
+            code_hash: code_hash.to_string(),
+            padding: None,
+        };
+        let hook = |contract_addr: &str| Hook {
+            contract_addr: HumanAddr::from(contract_addr),
+            code_hash: code_hash.clone(),
+        };
 
-        // it must be this key since that is who signed the query
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let HandleAnswer(result) = unpack_handle(&mut deps, env, msg);
-        assert_eq!(result, Uint128(0));
+        for name in ["hook0", "hook1"] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            handle(&mut deps, env, register(name, &code_hash))?;
+        }
+        // registering again only replaces the code hash
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let res = handle(&mut deps, env, register("hook0", &code_hash))?;
+        let answer: HookAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            HookAnswer::RegisterHook {
+                hooks: vec![hook("hook0"), hook("hook1")]
+            }
+        );
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(3),
-            },
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::Batch {
+            ops: vec![
+                CalcOp::Add(BinaryOp(Uint128(1), Uint128(2))).into(),
+                CalcOp::Div(BinaryOp(Uint128(1), Uint128(0))).into(),
+            ],
+            atomic: false,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
         };
+        let res = handle(&mut deps, env, msg)?;
+        let notification =
+            Binary(br#"{"calculation":{"id":0,"operation":"Add","result":"3"}}"#.to_vec());
+        let expected: Vec<CosmosMsg> = ["hook0", "hook1"]
+            .iter()
+            .map(|name| {
+                WasmMsg::Execute {
+                    contract_addr: HumanAddr::from(*name),
+                    callback_code_hash: code_hash.clone(),
+                    msg: notification.clone(),
+                    send: vec![],
+                }
+                .into()
+            })
+            .collect();
+        assert_eq!(res.messages, expected);
 
-        let raw_res = query(&mut deps, msg)?;
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        // other accounts aren't notified
+        let env = mock_env("other", &[]);
+        let res = handle(
+            &mut deps,
+            env,
+            HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2))),
+        )?;
+        assert!(res.messages.is_empty());
+
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::UnregisterHook {
+            contract_addr: HumanAddr::from("hook0"),
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg.clone())?;
+        let answer: HookAnswer = from_binary(&res.data.unwrap())?;
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![StoredCalculation {
-                    left_operand: Uint128(23),
-                    right_operand: Some(Uint128(50)),
-                    operation: "Div".to_string(),
-                    result: Uint128(0)
-                }],
-                total: Some(Uint128(1)),
+            answer,
+            HookAnswer::UnregisterHook {
+                hooks: vec![hook("hook1")]
+            }
+        );
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        assert_eq!(
+            handle(&mut deps, env, msg),
+            Err(ContractError::HookNotFound {
+                contract_addr: HumanAddr::from("hook0")
             }
+            .into())
+        );
+
+        // updates of the accumulator, variables and cells are calculations too
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::Accumulate {
+            op: AccumulatorOp::Add,
+            operand: Operand::Value(Uint128(5)),
+            session: None,
+            callback: None,
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg)?;
+        let expected: CosmosMsg = WasmMsg::Execute {
+            contract_addr: HumanAddr::from("hook1"),
+            callback_code_hash: code_hash.clone(),
+            msg: Binary(br#"{"calculation":{"id":1,"operation":"Add","result":"5"}}"#.to_vec()),
+            send: vec![],
+        }
+        .into();
+        assert_eq!(res.messages, vec![expected]);
+
+        let invalid = |reason: &str| ContractError::InvalidHook {
+            reason: reason.to_string(),
+        };
+        for (msg, error) in [
+            (
+                register("cosmos2contract", &code_hash),
+                invalid("the calculator can't call itself back"),
+            ),
+            (
+                register("hook2", "abc"),
+                invalid("code hashes are 64 hexadecimal digits"),
+            ),
+        ] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            assert_eq!(handle(&mut deps, env, msg), Err(error.into()));
+        }
+
+        for name in ["hook2", "hook3", "hook4"] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            handle(&mut deps, env, register(name, &code_hash))?;
+        }
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        assert_eq!(
+            handle(&mut deps, env, register("hook5", &code_hash)),
+            Err(ContractError::TooManyHooks { max: MAX_HOOKS }.into())
         );
 
         Ok(())
     }
 
     #[test]
-    fn div_by_zero() -> Result<(), StdError> {
+    fn fees() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        let env = mock_env("admin", &[]);
+        let prices: BTreeMap<String, Uint128> = vec![
+            ("add".to_string(), Uint128(5)),
+            ("batch".to_string(), Uint128(2)),
+        ]
+        .into_iter()
+        .collect();
+        let msg = InitMsg {
+            prices: Some(prices.clone()),
+            ..InitMsg::default()
+        };
+        init(&mut deps, env, msg)?;
 
-        let msg = HandleMsg::Div(BinaryOp(Uint128(23), Uint128(0)));
+        let answer: QueryAnswer = from_binary(&query(&deps, QueryMsg::Prices { padding: None })?)?;
+        assert_eq!(
+            answer,
+            QueryAnswer::Prices {
+                denom: "uscrt".to_string(),
+                prices,
+            }
+        );
 
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
+        let add = || HandleMsg::Add(BinaryOp(Uint128(1), Uint128(2)));
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(3, "uscrt"));
+        assert_eq!(
+            handle(&mut deps, env, add()),
+            Err(ContractError::FeeNotPaid {
+                price: Uint128(5),
+                denom: "uscrt".to_string(),
+            }
+            .into())
+        );
 
-        let res = handle(&mut deps, env, msg);
+        // everything beyond the price is sent back
+        let funds = vec![coin(7, "uscrt"), coin(2, "token")];
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &funds);
+        let res = handle(&mut deps, env, add())?;
+        let refund: CosmosMsg = BankMsg::Send {
+            from_address: HumanAddr::from("cosmos2contract"),
+            to_address: HumanAddr::from("qcYLPHTmmt6mhJpcp3UN"),
+            amount: vec![coin(2, "uscrt"), coin(2, "token")],
+        }
+        .into();
+        assert_eq!(res.messages, vec![refund]);
+
+        // unlisted actions are free
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+        let msg = HandleMsg::Sqrt(UnaryOp(Uint128(4)));
+        assert!(handle(&mut deps, env, msg)?.messages.is_empty());
+
+        let withdraw = |amount: u128| HandleMsg::WithdrawFees {
+            to: HumanAddr::from("treasury"),
+            amount: coin(amount, "uscrt"),
+            padding: None,
+        };
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
         assert_eq!(
-            res,
-            Err(StdError::generic_err("Divisor can't be zero")),
-            "failed raising error for divisio by zero"
+            handle(&mut deps, env, withdraw(5)),
+            Err(ContractError::NotAdmin.into())
+        );
+        let env = mock_env("admin", &[]);
+        assert_eq!(
+            handle(&mut deps, env, withdraw(6)),
+            Err(ContractError::NotEnoughFees {
+                available: Uint128(5),
+                denom: "uscrt".to_string(),
+            }
+            .into())
+        );
+
+        let env = mock_env("admin", &[]);
+        let res = handle(&mut deps, env, withdraw(5))?;
+        let send: CosmosMsg = BankMsg::Send {
+            from_address: HumanAddr::from("cosmos2contract"),
+            to_address: HumanAddr::from("treasury"),
+            amount: coins(5, "uscrt"),
+        }
+        .into();
+        assert_eq!(res.messages, vec![send]);
+        let answer: ConfigAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(
+            answer,
+            ConfigAnswer::WithdrawFees {
+                amount: coin(5, "uscrt"),
+                remaining: Uint128::zero(),
+            }
+        );
+
+        // batches cost their price for each operation
+        let batch = HandleMsg::Batch {
+            ops: vec![CalcOp::Sqrt(UnaryOp(Uint128(4))).into(); 3],
+            atomic: true,
+            record: None,
+            session: None,
+            memo: None,
+            tags: None,
+            log: None,
+            callback: None,
+            padding: None,
+        };
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(5, "uscrt"));
+        assert_eq!(
+            handle(&mut deps, env, batch.clone()),
+            Err(ContractError::FeeNotPaid {
+                price: Uint128(6),
+                denom: "uscrt".to_string(),
+            }
+            .into())
+        );
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(6, "uscrt"));
+        assert!(handle(&mut deps, env, batch)?.messages.is_empty());
+
+        // prices can only be set for actions
+        let unknown = |action: &str| -> BTreeMap<String, Uint128> {
+            vec![(action.to_string(), Uint128(1))].into_iter().collect()
+        };
+        let env = mock_env("admin", &[]);
+        let msg = HandleMsg::UpdateConfig(ConfigUpdate {
+            prices: Some(unknown("receive")),
+            ..ConfigUpdate::default()
+        });
+        assert_eq!(
+            handle(&mut deps, env.clone(), msg),
+            Err(ContractError::UnknownAction {
+                action: "receive".to_string()
+            }
+            .into())
+        );
+        let msg = InitMsg {
+            prices: Some(unknown("Add")),
+            ..InitMsg::default()
+        };
+        assert_eq!(
+            init(&mut deps, env, msg),
+            Err(ContractError::UnknownAction {
+                action: "Add".to_string()
+            }
+            .into())
         );
 
         Ok(())
     }
 
     #[test]
-    fn sqrt() -> Result<(), StdError> {
+    fn token_payments() -> Result<(), StdError> {
         let mut deps = my_mock_dependencies(&coins(2, "token"));
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        init(&mut deps, env, InitMsg {})?;
+        let env = mock_env("admin", &[]);
+        let token = PaymentToken {
+            contract_addr: HumanAddr::from("snip20"),
+            code_hash: "ef".repeat(32),
+        };
+        let msg = InitMsg {
+            prices: Some(
+                vec![("calculate".to_string(), Uint128(5))]
+                    .into_iter()
+                    .collect(),
+            ),
+            payment_token: Some(token.clone()),
+            ..InitMsg::default()
+        };
+        let res = init(&mut deps, env.clone(), msg)?;
+        assert_eq!(res.messages, vec![register_receive(&env, &token)?]);
 
-        let msg = HandleMsg::Sqrt(UnaryOp(Uint128(17)));
+        let receive = |amount: u128| HandleMsg::Receive {
+            sender: HumanAddr::from("qcYLPHTmmt6mhJpcp3UN"),
+            from: HumanAddr::from("owner"),
+            amount: Uint128(amount),
+            msg: Binary(br#"{"calculate":{"op":{"add":[{"value":"1"},{"value":"2"}]}}}"#.to_vec()),
+        };
 
-        // it must be this key since that is who signed the query
-        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(2, "token"));
-        let HandleAnswer(result) = unpack_handle(&mut deps, env, msg);
-        assert_eq!(result, Uint128(4));
+        let env = mock_env("other_token", &[]);
+        assert_eq!(
+            handle(&mut deps, env, receive(5)),
+            Err(ContractError::InvalidPayment {
+                reason: "only the payment token is accepted".to_string(),
+            }
+            .into())
+        );
+        let env = mock_env("snip20", &[]);
+        assert_eq!(
+            handle(&mut deps, env, receive(3)),
+            Err(ContractError::FeeNotPaid {
+                price: Uint128(5),
+                denom: "snip20".to_string(),
+            }
+            .into())
+        );
 
-        let msg = QueryMsg::WithPermit {
-            permit: serde_json::from_str(&PERMIT).unwrap(),
-            query: QueryWithPermit::CalculationHistory {
-                page: None,
-                page_size: Uint128(4),
-            },
+        // runs as the sender, and sends the overpayment back to the owner of the tokens
+        let env = mock_env("snip20", &[]);
+        let res = handle(&mut deps, env, receive(7))?;
+        let refund = transfer_msg(
+            HumanAddr::from("owner"),
+            Uint128(2),
+            None,
+            None,
+            BLOCK_SIZE,
+            token.code_hash.clone(),
+            token.contract_addr.clone(),
+        )?;
+        assert_eq!(res.messages, vec![refund]);
+        let answer: HandleAnswer = from_binary(&res.data.unwrap())?;
+        assert_eq!(answer.result, Uint128(3));
+        let (ids, _) = history_ids(&deps);
+        assert_eq!(ids, vec![0]);
+
+        // token fees are withdrawn by the address of the token
+        let withdraw = |amount: u128| HandleMsg::WithdrawFees {
+            to: HumanAddr::from("treasury"),
+            amount: coin(amount, "snip20"),
+            padding: None,
         };
+        let send = |amount: u128| {
+            transfer_msg(
+                HumanAddr::from("treasury"),
+                Uint128(amount),
+                None,
+                None,
+                BLOCK_SIZE,
+                token.code_hash.clone(),
+                token.contract_addr.clone(),
+            )
+        };
+        let env = mock_env("admin", &[]);
+        let res = handle(&mut deps, env, withdraw(3))?;
+        assert_eq!(res.messages, vec![send(3)?]);
 
-        let raw_res = query(&mut deps, msg)?;
-        let response_string = String::from_utf8(raw_res.clone().into()).unwrap();
-        let deserialized_result: QueryAnswer =
-            serde_json::from_str(response_string.as_str()).unwrap();
-        println!("the result is: {:?}", deserialized_result);
+        let new_token = PaymentToken {
+            contract_addr: HumanAddr::from("snip20_v2"),
+            code_hash: "01".repeat(32),
+        };
+        let msg = HandleMsg::SetPaymentToken {
+            contract_addr: new_token.contract_addr.clone(),
+            code_hash: new_token.code_hash.clone(),
+            padding: None,
+        };
+        let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
         assert_eq!(
-            deserialized_result,
-            QueryAnswer::CalculationHistory {
-                calcs: vec![StoredCalculation {
-                    left_operand: Uint128(17),
-                    right_operand: None,
-                    operation: "Sqrt".to_string(),
-                    result: Uint128(4)
-                }],
-                total: Some(Uint128(1)),
-            }
+            handle(&mut deps, env, msg.clone()),
+            Err(ContractError::NotAdmin.into())
         );
+        let env = mock_env("admin", &[]);
+        let res = handle(&mut deps, env.clone(), msg)?;
+        assert_eq!(res.messages, vec![register_receive(&env, &new_token)?]);
+
+        // the fees of the previous token can still be withdrawn
+        let env = mock_env("admin", &[]);
+        let res = handle(&mut deps, env, withdraw(2))?;
+        assert_eq!(res.messages, vec![send(2)?]);
+
+        // accounts can't make the calculator call a payment token, current or previous
+        for token in [&token, &new_token] {
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &coins(5, "uscrt"));
+            let msg = HandleMsg::Calculate {
+                op: CalcOp::Add(BinaryOp(
+                    Operand::Value(Uint128(1)),
+                    Operand::Value(Uint128(2)),
+                )),
+                record: None,
+                session: None,
+                memo: None,
+                tags: None,
+                log: None,
+                callback: Some(Callback {
+                    contract_addr: token.contract_addr.clone(),
+                    code_hash: token.code_hash.clone(),
+                    msg_template: r#"{"transfer":{"recipient":"thief","amount":"{result}000"}}"#
+                        .to_string(),
+                }),
+                padding: None,
+            };
+            assert_eq!(
+                handle(&mut deps, env, msg),
+                Err(ContractError::InvalidCallback {
+                    reason: "payment tokens can't be called".to_string(),
+                }
+                .into())
+            );
+
+            let env = mock_env("qcYLPHTmmt6mhJpcp3UN", &[]);
+            let msg = HandleMsg::RegisterHook {
+                contract_addr: token.contract_addr.clone(),
+                code_hash: token.code_hash.clone(),
+                padding: None,
+            };
+            assert_eq!(
+                handle(&mut deps, env, msg),
+                Err(ContractError::InvalidHook {
+                    reason: "payment tokens can't be called".to_string(),
+                }
+                .into())
+            );
+        }
+
         Ok(())
     }
 }
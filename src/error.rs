@@ -0,0 +1,295 @@
+use cosmwasm_std::{to_vec, HumanAddr, StdError, Uint128};
+use serde::Serialize;
+use snafu::Snafu;
+
+use crate::msg::CalculatorPermission;
+
+pub type ContractResult<T> = Result<T, ContractError>;
+
+// The errors of the contract. Each one is sent along with a code that never changes, so clients
+// don't need to match messages.
+#[derive(Snafu, Debug, PartialEq)]
+pub enum ContractError {
+    #[snafu(display("{}", source))]
+    Std { source: StdError },
+
+    #[snafu(display("Overflow in {} operation", op))]
+    Overflow { op: String },
+    #[snafu(display("Underflow in {} operation", op))]
+    Underflow { op: String },
+    #[snafu(display("Divisor can't be zero"))]
+    DivisionByZero,
+
+    #[snafu(display("This is an admin command and can only be run from the admin address"))]
+    NotAdmin,
+    #[snafu(display("No permission to query {}, got permissions {:?}", needed, granted))]
+    PermissionDenied {
+        needed: String,
+        granted: Vec<CalculatorPermission>,
+    },
+    #[snafu(display("{}", reason))]
+    PermitRevoked { reason: String },
+    #[snafu(display("{}", reason))]
+    InvalidPermit { reason: String },
+    #[snafu(display("Global stats are disabled"))]
+    GlobalStatsDisabled,
+
+    #[snafu(display("Calculation {} not found", id))]
+    CalculationNotFound { id: u64 },
+    #[snafu(display("There is no calculation in the history"))]
+    EmptyHistory,
+    #[snafu(display("There is no calculation {} back in the history", back))]
+    NoCalculation { back: u32 },
+    #[snafu(display("Variable {} is not set", name))]
+    VariableNotSet { name: String },
+    #[snafu(display("Session {} not found", id))]
+    SessionNotFound { id: u64 },
+    #[snafu(display("Formula {} not found", name))]
+    FormulaNotFound { name: String },
+    #[snafu(display("Version {} of formula {} not found", version, name))]
+    FormulaVersionNotFound { name: String, version: u32 },
+    #[snafu(display("Nothing to undo"))]
+    NothingToUndo,
+    #[snafu(display("Nothing to redo"))]
+    NothingToRedo,
+    #[snafu(display("Hook {} not found", contract_addr))]
+    HookNotFound { contract_addr: HumanAddr },
+
+    #[snafu(display("{} must be at least 1", name))]
+    InvalidLimit { name: String },
+    #[snafu(display("Memos can't be longer than {} bytes", max))]
+    MemoTooLong { max: usize },
+    #[snafu(display("Tags must be 1 to {} bytes long", max_length))]
+    InvalidTag { max_length: usize },
+    #[snafu(display("Calculations can't have more than {} tags", max))]
+    TooManyTags { max: usize },
+    #[snafu(display("Session {} has ended", id))]
+    SessionEnded { id: u64 },
+    #[snafu(display("Session labels can't be longer than {} bytes", max))]
+    SessionLabelTooLong { max: usize },
+    #[snafu(display("Batch can't be empty"))]
+    EmptyBatch,
+    #[snafu(display("Batch can't have more than {} operations", max))]
+    BatchTooLarge { max: u32 },
+    #[snafu(display("Batch operation {} failed: {}", index, error))]
+    BatchOperationFailed {
+        index: usize,
+        error: Box<ContractError>,
+    },
+    #[snafu(display("Variable names must be 1 to {} bytes long", max_length))]
+    InvalidVariableName { max_length: usize },
+    #[snafu(display("Can't have more than {} variables", max))]
+    TooManyVariables { max: u32 },
+    #[snafu(display("Formula names must be 1 to {} bytes long", max_length))]
+    InvalidFormulaName { max_length: usize },
+    #[snafu(display("Formula descriptions can't be longer than {} bytes", max))]
+    DescriptionTooLong { max: usize },
+    #[snafu(display("Version {} of formula {} is already published", version, name))]
+    FormulaVersionExists { name: String, version: u32 },
+    #[snafu(display("Formula {} takes {} arguments, got {}", name, expected, got))]
+    WrongArgumentCount {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    #[snafu(display("Invalid formula: {}", reason))]
+    InvalidFormula { reason: String },
+    // `pos` is the byte offset in the expression
+    #[snafu(display("Invalid formula: {} at position {}", reason, pos))]
+    InvalidExpression { pos: usize, reason: String },
+    #[snafu(display("Invalid cell name {}", name))]
+    InvalidCellName { name: String },
+    #[snafu(display("Sheets can't have more than {} cells", max))]
+    TooManyCells { max: usize },
+    #[snafu(display(
+        "Cell {} can't reference itself, directly or through other cells",
+        cell
+    ))]
+    CyclicReference { cell: String },
+    #[snafu(display("Setting cell {} would recompute more than {} cells", cell, max))]
+    TooManyRecomputedCells { cell: String, max: usize },
+    #[snafu(display("Cell {}: {}", cell, error))]
+    CellFailed {
+        cell: String,
+        error: Box<ContractError>,
+    },
+    #[snafu(display("Invalid callback: {}", reason))]
+    InvalidCallback { reason: String },
+    #[snafu(display("Callback messages can't be longer than {} bytes", max))]
+    CallbackTooLong { max: usize },
+    #[snafu(display("Invalid hook: {}", reason))]
+    InvalidHook { reason: String },
+    #[snafu(display("Can't have more than {} hooks", max))]
+    TooManyHooks { max: usize },
+    #[snafu(display("This operation costs {}{}", price, denom))]
+    FeeNotPaid { price: Uint128, denom: String },
+    #[snafu(display("Only {}{} of fees can be withdrawn", available, denom))]
+    NotEnoughFees { available: Uint128, denom: String },
+    #[snafu(display("Invalid payment token: {}", reason))]
+    InvalidPaymentToken { reason: String },
+    #[snafu(display("Invalid payment: {}", reason))]
+    InvalidPayment { reason: String },
+    #[snafu(display("There is no action named {}", action))]
+    UnknownAction { action: String },
+}
+
+impl ContractError {
+    // Codes are grouped by kind of failure. They must never be reused for other errors.
+    pub fn code(&self) -> u16 {
+        match self {
+            ContractError::Std { .. } => 1,
+
+            ContractError::Overflow { .. } => 10,
+            ContractError::Underflow { .. } => 11,
+            ContractError::DivisionByZero => 12,
+
+            ContractError::NotAdmin => 20,
+            ContractError::PermissionDenied { .. } => 21,
+            ContractError::PermitRevoked { .. } => 22,
+            ContractError::InvalidPermit { .. } => 23,
+            ContractError::GlobalStatsDisabled => 24,
+
+            ContractError::CalculationNotFound { .. } => 30,
+            ContractError::EmptyHistory => 31,
+            ContractError::NoCalculation { .. } => 32,
+            ContractError::VariableNotSet { .. } => 33,
+            ContractError::SessionNotFound { .. } => 34,
+            ContractError::FormulaNotFound { .. } => 35,
+            ContractError::FormulaVersionNotFound { .. } => 36,
+            ContractError::NothingToUndo => 37,
+            ContractError::NothingToRedo => 38,
+            ContractError::HookNotFound { .. } => 39,
+
+            ContractError::InvalidLimit { .. } => 40,
+            ContractError::MemoTooLong { .. } => 41,
+            ContractError::InvalidTag { .. } => 42,
+            ContractError::TooManyTags { .. } => 43,
+            ContractError::SessionEnded { .. } => 44,
+            ContractError::SessionLabelTooLong { .. } => 45,
+            ContractError::EmptyBatch => 46,
+            ContractError::BatchTooLarge { .. } => 47,
+            ContractError::BatchOperationFailed { .. } => 48,
+            ContractError::InvalidVariableName { .. } => 49,
+            ContractError::TooManyVariables { .. } => 50,
+            ContractError::InvalidFormulaName { .. } => 51,
+            ContractError::DescriptionTooLong { .. } => 52,
+            ContractError::FormulaVersionExists { .. } => 53,
+            ContractError::WrongArgumentCount { .. } => 54,
+            ContractError::InvalidFormula { .. } => 55,
+            ContractError::InvalidExpression { .. } => 56,
+            ContractError::InvalidCellName { .. } => 57,
+            ContractError::TooManyCells { .. } => 58,
+            ContractError::CyclicReference { .. } => 59,
+            ContractError::TooManyRecomputedCells { .. } => 60,
+            ContractError::CellFailed { .. } => 61,
+            ContractError::InvalidCallback { .. } => 62,
+            ContractError::CallbackTooLong { .. } => 63,
+            ContractError::InvalidHook { .. } => 64,
+            ContractError::TooManyHooks { .. } => 65,
+            ContractError::FeeNotPaid { .. } => 66,
+            ContractError::NotEnoughFees { .. } => 67,
+            ContractError::InvalidPaymentToken { .. } => 68,
+            ContractError::InvalidPayment { .. } => 69,
+            ContractError::UnknownAction { .. } => 70,
+        }
+    }
+}
+
+impl From<StdError> for ContractError {
+    fn from(source: StdError) -> Self {
+        ContractError::Std { source }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorPayload {
+    code: u16,
+    msg: String,
+}
+
+// Every error, including those of the std library, is sent as a JSON payload with its code.
+impl From<ContractError> for StdError {
+    fn from(err: ContractError) -> Self {
+        let payload = ErrorPayload {
+            code: err.code(),
+            msg: err.to_string(),
+        };
+        match to_vec(&payload).map(String::from_utf8) {
+            Ok(Ok(payload)) => StdError::generic_err(payload),
+            _ => StdError::generic_err(payload.msg),
+        }
+    }
+}
+
+// the length of every error message in privacy mode
+pub const PRIVATE_ERROR_LENGTH: usize = 32;
+
+// The kinds of failure that privacy mode reports, instead of the check that failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrivateError {
+    InvalidRequest = 1,
+    NotFound = 2,
+    Arithmetic = 3,
+    Unauthorized = 4,
+    Internal = 5,
+}
+
+impl PrivateError {
+    pub fn of(err: &ContractError) -> Self {
+        match err {
+            ContractError::Std { source } => match source {
+                StdError::NotFound { .. } => PrivateError::NotFound,
+                StdError::Unauthorized { .. } => PrivateError::Unauthorized,
+                StdError::Underflow { .. } => PrivateError::Arithmetic,
+                _ => PrivateError::Internal,
+            },
+            ContractError::BatchOperationFailed { error, .. }
+            | ContractError::CellFailed { error, .. } => PrivateError::of(error),
+
+            ContractError::Overflow { .. }
+            | ContractError::Underflow { .. }
+            | ContractError::DivisionByZero => PrivateError::Arithmetic,
+
+            ContractError::NotAdmin
+            | ContractError::PermissionDenied { .. }
+            | ContractError::PermitRevoked { .. }
+            | ContractError::InvalidPermit { .. }
+            | ContractError::GlobalStatsDisabled => PrivateError::Unauthorized,
+
+            ContractError::CalculationNotFound { .. }
+            | ContractError::EmptyHistory
+            | ContractError::NoCalculation { .. }
+            | ContractError::VariableNotSet { .. }
+            | ContractError::SessionNotFound { .. }
+            | ContractError::FormulaNotFound { .. }
+            | ContractError::FormulaVersionNotFound { .. }
+            | ContractError::NothingToUndo
+            | ContractError::NothingToRedo
+            | ContractError::HookNotFound { .. } => PrivateError::NotFound,
+
+            _ => PrivateError::InvalidRequest,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            PrivateError::InvalidRequest => "invalid request",
+            PrivateError::NotFound => "not found",
+            PrivateError::Arithmetic => "arithmetic error",
+            PrivateError::Unauthorized => "unauthorized",
+            PrivateError::Internal => "internal error",
+        }
+    }
+}
+
+// Replaces an error with the code of its kind, padded so that all of them have the same length.
+pub fn private_error(err: &ContractError) -> StdError {
+    let kind = PrivateError::of(err);
+    let msg = format!("Error {}: {}", kind.code(), kind.description());
+
+    StdError::generic_err(format!("{:<1$}", msg, PRIVATE_ERROR_LENGTH))
+}
@@ -0,0 +1,309 @@
+use std::fmt;
+
+use cosmwasm_std::Uint128;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ContractError, ContractResult};
+use crate::math;
+
+pub const MAX_FORMULA_LENGTH: usize = 256;
+pub const MAX_FORMULA_PARAMS: usize = 16;
+// bounds the nesting of parentheses, and so the recursion of the parser. Evaluation recurses once
+// per operator, which MAX_FORMULA_LENGTH already bounds.
+const MAX_FORMULA_DEPTH: u32 = 32;
+
+// The parsed expression of a formula. Parameters are referenced by their position, so that
+// evaluating doesn't need to look names up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Expr {
+    Value(Uint128),
+    Param(u32),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Sqrt(Box<Expr>),
+}
+
+impl Expr {
+    // uses the same checked arithmetic as the calculator's operations
+    pub fn evaluate(&self, args: &[Uint128]) -> ContractResult<Uint128> {
+        match self {
+            Expr::Value(value) => Ok(*value),
+            Expr::Param(index) => Ok(args[*index as usize]),
+            Expr::Add(left, right) => math::add(left.evaluate(args)?, right.evaluate(args)?),
+            Expr::Sub(left, right) => math::sub(left.evaluate(args)?, right.evaluate(args)?),
+            Expr::Mul(left, right) => math::mul(left.evaluate(args)?, right.evaluate(args)?),
+            Expr::Div(left, right) => math::div(left.evaluate(args)?, right.evaluate(args)?),
+            Expr::Sqrt(radicand) => Ok(math::sqrt(radicand.evaluate(args)?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(u128),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "`{}`", number),
+            Token::Ident(ident) => write!(f, "`{}`", ident),
+            Token::Plus => write!(f, "`+`"),
+            Token::Minus => write!(f, "`-`"),
+            Token::Star => write!(f, "`*`"),
+            Token::Slash => write!(f, "`/`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+        }
+    }
+}
+
+// Parses expressions such as `sqrt(a * a + b * b) / 2`, with the usual precedence of `*` and `/`
+// over `+` and `-`. Every name must be one of `params`.
+pub fn parse(expr: &str, params: &[String]) -> ContractResult<Expr> {
+    validate_params(params)?;
+    let (expr, _) = parse_expr(expr, params.to_vec(), false)?;
+    Ok(expr)
+}
+
+// Like `parse`, but any name is accepted. The names are returned in the order they first appear,
+// which is the order in which `evaluate` expects their values.
+pub fn parse_with_names(expr: &str) -> ContractResult<(Expr, Vec<String>)> {
+    parse_expr(expr, vec![], true)
+}
+
+fn parse_expr(expr: &str, params: Vec<String>, open: bool) -> ContractResult<(Expr, Vec<String>)> {
+    if expr.len() > MAX_FORMULA_LENGTH {
+        return Err(invalid(format!(
+            "expressions can't be longer than {} bytes",
+            MAX_FORMULA_LENGTH
+        )));
+    }
+
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        position: 0,
+        end: expr.len(),
+        params,
+        open,
+    };
+    let expr = parser.expr(0)?;
+    match parser.peek() {
+        None => Ok((expr, parser.params)),
+        Some(token) => Err(invalid_at(parser.offset(), format!("unexpected {}", token))),
+    }
+}
+
+fn validate_params(params: &[String]) -> ContractResult<()> {
+    if params.len() > MAX_FORMULA_PARAMS {
+        return Err(invalid(format!(
+            "formulas can't have more than {} parameters",
+            MAX_FORMULA_PARAMS
+        )));
+    }
+    for (index, param) in params.iter().enumerate() {
+        if !is_identifier(param) || param == "sqrt" {
+            return Err(invalid(format!("bad parameter name `{}`", param)));
+        }
+        if params[..index].contains(param) {
+            return Err(invalid(format!("duplicate parameter `{}`", param)));
+        }
+    }
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn invalid(reason: String) -> ContractError {
+    ContractError::InvalidFormula { reason }
+}
+
+// an error at byte `pos` of the expression
+fn invalid_at(pos: usize, reason: String) -> ContractError {
+    ContractError::InvalidExpression { pos, reason }
+}
+
+// Each token is returned with its byte offset in the expression.
+fn tokenize(expr: &str) -> ContractResult<Vec<(usize, Token)>> {
+    let mut tokens = vec![];
+    let mut chars = expr.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&(_, digit)) = chars.peek() {
+                if !digit.is_ascii_digit() {
+                    break;
+                }
+                number.push(digit);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| invalid_at(offset, format!("number {} is too large", number)))?;
+            tokens.push((offset, Token::Number(number)));
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push((offset, Token::Ident(ident)));
+            continue;
+        }
+
+        let token = match c {
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            _ => return Err(invalid_at(offset, format!("unexpected character `{}`", c))),
+        };
+        tokens.push((offset, token));
+        chars.next();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    // the length of the expression, where errors about its end are reported
+    end: usize,
+    params: Vec<String>,
+    // whether unknown names are added to `params` instead of being rejected
+    open: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    // the offset of the next token, or the end of the expression if there are no more
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |&(offset, _)| offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> ContractResult<()> {
+        let offset = self.offset();
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(invalid_at(
+                offset,
+                format!("expected {}, got {}", expected, token),
+            )),
+            None => Err(invalid_at(offset, format!("expected {}", expected))),
+        }
+    }
+
+    // expr := term (("+" | "-") term)*
+    fn expr(&mut self, depth: u32) -> ContractResult<Expr> {
+        if depth > MAX_FORMULA_DEPTH {
+            return Err(invalid_at(
+                self.offset(),
+                "expression is nested too deeply".to_string(),
+            ));
+        }
+
+        let mut left = self.term(depth)?;
+        loop {
+            left = match self.peek() {
+                Some(Token::Plus) => {
+                    self.next();
+                    Expr::Add(Box::new(left), Box::new(self.term(depth)?))
+                }
+                Some(Token::Minus) => {
+                    self.next();
+                    Expr::Sub(Box::new(left), Box::new(self.term(depth)?))
+                }
+                _ => return Ok(left),
+            };
+        }
+    }
+
+    // term := factor (("*" | "/") factor)*
+    fn term(&mut self, depth: u32) -> ContractResult<Expr> {
+        let mut left = self.factor(depth)?;
+        loop {
+            left = match self.peek() {
+                Some(Token::Star) => {
+                    self.next();
+                    Expr::Mul(Box::new(left), Box::new(self.factor(depth)?))
+                }
+                Some(Token::Slash) => {
+                    self.next();
+                    Expr::Div(Box::new(left), Box::new(self.factor(depth)?))
+                }
+                _ => return Ok(left),
+            };
+        }
+    }
+
+    // factor := number | param | "sqrt" "(" expr ")" | "(" expr ")"
+    fn factor(&mut self, depth: u32) -> ContractResult<Expr> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Value(Uint128(number))),
+            Some(Token::Ident(ident)) if ident == "sqrt" => {
+                self.expect(Token::LParen)?;
+                let radicand = self.expr(depth + 1)?;
+                self.expect(Token::RParen)?;
+                Ok(Expr::Sqrt(Box::new(radicand)))
+            }
+            Some(Token::Ident(ident)) => match self.params.iter().position(|p| *p == ident) {
+                Some(index) => Ok(Expr::Param(index as u32)),
+                None if self.open => {
+                    self.params.push(ident);
+                    Ok(Expr::Param(self.params.len() as u32 - 1))
+                }
+                None => Err(invalid_at(offset, format!("unknown parameter `{}`", ident))),
+            },
+            Some(Token::LParen) => {
+                let expr = self.expr(depth + 1)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(token) => Err(invalid_at(offset, format!("unexpected {}", token))),
+            None => Err(invalid_at(
+                offset,
+                "unexpected end of expression".to_string(),
+            )),
+        }
+    }
+}
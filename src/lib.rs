@@ -1,5 +1,9 @@
 pub mod contract;
+pub mod error;
+pub mod formula;
+pub mod math;
 pub mod msg;
+pub mod sheet;
 pub mod state;
 mod test_utils;
 
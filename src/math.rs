@@ -0,0 +1,65 @@
+use cosmwasm_std::Uint128;
+use integer_sqrt::IntegerSquareRoot;
+
+use crate::error::{ContractError, ContractResult};
+use crate::msg::CalcOp;
+
+// The arithmetic of the calculator. It never touches storage, so that handles and the stateless
+// `compute` query run the exact same code.
+pub fn compute(op: &CalcOp) -> ContractResult<Uint128> {
+    match op {
+        CalcOp::Add(calculation) => add(calculation.0, calculation.1),
+        CalcOp::Sub(calculation) => sub(calculation.0, calculation.1),
+        CalcOp::Mul(calculation) => mul(calculation.0, calculation.1),
+        CalcOp::Div(calculation) => div(calculation.0, calculation.1),
+        CalcOp::Sqrt(calculation) => Ok(sqrt(calculation.0)),
+    }
+}
+
+pub fn add(left_operand: Uint128, right_operand: Uint128) -> ContractResult<Uint128> {
+    left_operand
+        .u128()
+        .checked_add(right_operand.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Overflow {
+            op: "Add".to_string(),
+        })
+}
+
+pub fn sub(left_operand: Uint128, right_operand: Uint128) -> ContractResult<Uint128> {
+    left_operand
+        .u128()
+        .checked_sub(right_operand.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Underflow {
+            op: "Sub".to_string(),
+        })
+}
+
+pub fn mul(left_operand: Uint128, right_operand: Uint128) -> ContractResult<Uint128> {
+    left_operand
+        .u128()
+        .checked_mul(right_operand.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Overflow {
+            op: "Mul".to_string(),
+        })
+}
+
+pub fn div(left_operand: Uint128, right_operand: Uint128) -> ContractResult<Uint128> {
+    if right_operand == Uint128::zero() {
+        return Err(ContractError::DivisionByZero);
+    }
+
+    left_operand
+        .u128()
+        .checked_div(right_operand.u128())
+        .map(Uint128::from)
+        .ok_or_else(|| ContractError::Underflow {
+            op: "Div".to_string(),
+        })
+}
+
+pub fn sqrt(radicand: Uint128) -> Uint128 {
+    Uint128::from(radicand.u128().integer_sqrt())
+}
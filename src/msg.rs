@@ -1,14 +1,39 @@
-use cosmwasm_std::Uint128;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use cosmwasm_std::{Binary, Coin, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::StoredCalculation;
+use crate::state::{Config, Hook, PaymentToken, Session, StoredCalculation};
 use secret_toolkit::permit::Permit;
 
-#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-// nothing to initialize in this contract
-pub struct InitMsg {}
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct InitMsg {
+    // defaults to the sender of the init message
+    pub admin: Option<HumanAddr>,
+    // maximum number of calculations kept in the history of each account
+    pub max_history: Option<u32>,
+    // whether the public `global_stats` query is enabled, defaults to true
+    pub global_stats_enabled: Option<bool>,
+    pub max_batch_size: Option<u32>,
+    pub max_variables: Option<u32>,
+    // whether errors only tell which kind of failure happened, defaults to false
+    pub privacy_mode: Option<bool>,
+    // the denom of the prices, defaults to `uscrt`
+    pub fee_denom: Option<String>,
+    // the price of each action, by the name it logs as `action`. by default everything is free.
+    // batches cost their price for each of their operations
+    pub prices: Option<BTreeMap<String, Uint128>>,
+    // a SNIP-20 token that can pay for actions too, at the same prices
+    pub payment_token: Option<PaymentToken>,
+}
 
+// The `padding` of messages is ignored, it lets clients hide the length of what they send. The
+// shorthand operations keep their original format and can't be padded, `calculate` can.
+//
+// Handles with a price must be sent along with it, in the fee denom or through `receive`. Anything
+// sent beyond the price is sent back.
 #[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum HandleMsg {
@@ -17,28 +42,505 @@ pub enum HandleMsg {
     Mul(BinaryOp),
     Div(BinaryOp),
     Sqrt(UnaryOp),
+    // like the operations above, with per-call options
+    Calculate {
+        op: CalcOp<Operand>,
+        // overrides the account's `record_history` preference
+        record: Option<bool>,
+        session: Option<u64>,
+        memo: Option<String>,
+        tags: Option<Vec<String>>,
+        // overrides the account's `log` preference
+        log: Option<LogSettings>,
+        // sends the result to another contract. the calculation is then always recorded
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    // runs the operations in order. if `atomic`, any failure reverts the whole batch
+    Batch {
+        ops: Vec<CalcOp<Operand>>,
+        atomic: bool,
+        record: Option<bool>,
+        session: Option<u64>,
+        memo: Option<String>,
+        tags: Option<Vec<String>>,
+        log: Option<LogSettings>,
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    // calculations can be added to an open session with their `session` field
+    StartSession {
+        label: String,
+        padding: Option<String>,
+    },
+    EndSession {
+        id: u64,
+        padding: Option<String>,
+    },
+    ClearHistory {
+        padding: Option<String>,
+    },
+    // replaces the tags of a calculation
+    TagCalculation {
+        id: u64,
+        tags: Vec<String>,
+        padding: Option<String>,
+    },
+    DeleteCalculation {
+        id: u64,
+        padding: Option<String>,
+    },
+    // undoes the latest calculation that isn't undone yet, reverting the variable, cell or
+    // accumulator it changed. setting a cell is undone at once with the cells it recomputed.
+    // undone calculations stay in the history, marked as such. calculations that change a
+    // variable, cell or accumulator are recorded even if the account doesn't record its history,
+    // so they can always be undone
+    Undo {
+        padding: Option<String>,
+    },
+    // redoes the latest undone calculation. making a new calculation discards what can be redone
+    Redo {
+        padding: Option<String>,
+    },
+    SetRetention {
+        max_entries: Option<u32>,
+        ttl_blocks: Option<u64>,
+        padding: Option<String>,
+    },
+    // `log` is left unchanged if not given
+    SetPreferences {
+        record_history: bool,
+        log: Option<LogSettings>,
+        padding: Option<String>,
+    },
+    SetVar {
+        name: String,
+        value: Uint128,
+        session: Option<u64>,
+        padding: Option<String>,
+    },
+    // an unset variable is incremented or decremented from zero
+    IncrVar {
+        name: String,
+        amount: Uint128,
+        session: Option<u64>,
+        padding: Option<String>,
+    },
+    DecrVar {
+        name: String,
+        amount: Uint128,
+        session: Option<u64>,
+        padding: Option<String>,
+    },
+    ClearVar {
+        name: String,
+        session: Option<u64>,
+        padding: Option<String>,
+    },
+    // applies `op` to the caller's accumulator, which starts at zero, and `operand`
+    Accumulate {
+        op: AccumulatorOp,
+        operand: Operand,
+        session: Option<u64>,
+        // sends the new value of the accumulator to another contract
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    ResetAccumulator {
+        session: Option<u64>,
+        padding: Option<String>,
+    },
+    // defines or replaces a formula of the caller, e.g. `sqrt(a * a + b * b)` with params
+    // `["a", "b"]`
+    DefineFormula {
+        name: String,
+        params: Vec<String>,
+        expr: String,
+        padding: Option<String>,
+    },
+    Invoke {
+        name: String,
+        args: Vec<Uint128>,
+        record: Option<bool>,
+        session: Option<u64>,
+        memo: Option<String>,
+        tags: Option<Vec<String>>,
+        log: Option<LogSettings>,
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    // publishes a formula that any account can invoke. published versions can't be changed
+    PublishFormula(PublishFormulaMsg),
+    DeprecateFormula {
+        name: String,
+        version: u32,
+        deprecated: bool,
+        padding: Option<String>,
+    },
+    InvokePublic {
+        author: HumanAddr,
+        name: String,
+        version: u32,
+        args: Vec<Uint128>,
+        record: Option<bool>,
+        session: Option<u64>,
+        memo: Option<String>,
+        tags: Option<Vec<String>>,
+        log: Option<LogSettings>,
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    // sets a cell of the caller's sheet, e.g. `A3` to the formula `A1 + A2`
+    SetCell {
+        cell: String,
+        content: CellContent,
+        // the session of the calculations of the cells that are recomputed
+        session: Option<u64>,
+        // sends the new value of the cell to another contract
+        callback: Option<Callback>,
+        padding: Option<String>,
+    },
+    UpdateConfig(ConfigUpdate),
+    // registering a registered contract again replaces its code hash
+    RegisterHook {
+        contract_addr: HumanAddr,
+        code_hash: String,
+        padding: Option<String>,
+    },
+    UnregisterHook {
+        contract_addr: HumanAddr,
+        padding: Option<String>,
+    },
+    // sends collected fees, admin only. the denom of fees paid in a token is its address, which
+    // works for previous payment tokens too
+    WithdrawFees {
+        to: HumanAddr,
+        amount: Coin,
+        padding: Option<String>,
+    },
+    // registers the calculator with the token, so that it calls `receive`. admin only
+    SetPaymentToken {
+        contract_addr: HumanAddr,
+        code_hash: String,
+        padding: Option<String>,
+    },
+    // Called by the payment token when `sender` sends tokens to the calculator. `msg` is the handle
+    // message to run, which runs as `sender` and is paid with `amount`. Overpayment is sent back
+    // to `from`, whose tokens were sent.
+    Receive {
+        sender: HumanAddr,
+        from: HumanAddr,
+        amount: Uint128,
+        msg: Binary,
+    },
+}
+
+// The actions that can have a price: every `action` but `receive`, which is paid as the message
+// it carries.
+pub const PRICED_ACTIONS: &[&str] = &[
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "sqrt",
+    "calculate",
+    "batch",
+    "start_session",
+    "end_session",
+    "clear_history",
+    "tag_calculation",
+    "delete_calculation",
+    "undo",
+    "redo",
+    "set_retention",
+    "set_preferences",
+    "set_var",
+    "incr_var",
+    "decr_var",
+    "clear_var",
+    "accumulate",
+    "reset_accumulator",
+    "define_formula",
+    "invoke",
+    "publish_formula",
+    "deprecate_formula",
+    "invoke_public",
+    "set_cell",
+    "update_config",
+    "register_hook",
+    "unregister_hook",
+    "withdraw_fees",
+    "set_payment_token",
+];
+
+impl HandleMsg {
+    // the `action` attribute of the handle's log
+    pub fn action(&self) -> &'static str {
+        match self {
+            HandleMsg::Add(_) => "add",
+            HandleMsg::Sub(_) => "sub",
+            HandleMsg::Mul(_) => "mul",
+            HandleMsg::Div(_) => "div",
+            HandleMsg::Sqrt(_) => "sqrt",
+            HandleMsg::Calculate { .. } => "calculate",
+            HandleMsg::Batch { .. } => "batch",
+            HandleMsg::StartSession { .. } => "start_session",
+            HandleMsg::EndSession { .. } => "end_session",
+            HandleMsg::ClearHistory { .. } => "clear_history",
+            HandleMsg::TagCalculation { .. } => "tag_calculation",
+            HandleMsg::DeleteCalculation { .. } => "delete_calculation",
+            HandleMsg::Undo { .. } => "undo",
+            HandleMsg::Redo { .. } => "redo",
+            HandleMsg::SetRetention { .. } => "set_retention",
+            HandleMsg::SetPreferences { .. } => "set_preferences",
+            HandleMsg::SetVar { .. } => "set_var",
+            HandleMsg::IncrVar { .. } => "incr_var",
+            HandleMsg::DecrVar { .. } => "decr_var",
+            HandleMsg::ClearVar { .. } => "clear_var",
+            HandleMsg::Accumulate { .. } => "accumulate",
+            HandleMsg::ResetAccumulator { .. } => "reset_accumulator",
+            HandleMsg::DefineFormula { .. } => "define_formula",
+            HandleMsg::Invoke { .. } => "invoke",
+            HandleMsg::PublishFormula(_) => "publish_formula",
+            HandleMsg::DeprecateFormula { .. } => "deprecate_formula",
+            HandleMsg::InvokePublic { .. } => "invoke_public",
+            HandleMsg::SetCell { .. } => "set_cell",
+            HandleMsg::UpdateConfig(_) => "update_config",
+            HandleMsg::RegisterHook { .. } => "register_hook",
+            HandleMsg::UnregisterHook { .. } => "unregister_hook",
+            HandleMsg::WithdrawFees { .. } => "withdraw_fees",
+            HandleMsg::SetPaymentToken { .. } => "set_payment_token",
+            HandleMsg::Receive { .. } => "receive",
+        }
+    }
 }
 
+// Logs are public, so by default handles only log their `action`, encrypted so that only the
+// sender can read it. Accounts opt into more through their preferences or per call.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct LogSettings {
+    pub detail: LogDetail,
+    // whether the attributes are logged in plaintext, readable by anyone
+    pub plaintext: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogDetail {
+    Action,
+    // also the `operation` and `calculation_id` of each calculation
+    Calculation,
+    // also the operands, or formula and arguments, and `result` of each calculation
+    Full,
+}
+
+// deriving `Default` for enums needs a newer compiler than the one CI uses
+#[allow(clippy::derivable_impls)]
+impl Default for LogDetail {
+    fn default() -> Self {
+        LogDetail::Action
+    }
+}
+
+// The message sent to `contract_addr` is `msg_template` with every `{result}` replaced by the
+// result, e.g. `{"deposit":{"amount":"{result}"}}`. Batches send one message per successful
+// operation, `accumulate` and `set_cell` send the new value of the accumulator or the cell.
+// Callbacks and hooks can't go to a payment token, current or previous.
 #[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
-pub struct UnaryOp(pub Uint128);
+pub struct Callback {
+    pub contract_addr: HumanAddr,
+    pub code_hash: String,
+    pub msg_template: String,
+}
+
+// Sent to each hook of an account for every calculation the account makes, in the order they are
+// made, including the updates of its variables, accumulator and cells. `id` is missing for
+// calculations that aren't recorded. A hook that fails makes the whole handle fail, so accounts
+// should only register contracts they trust.
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum HookMsg {
+    Calculation {
+        id: Option<u64>,
+        operation: String,
+        result: Uint128,
+    },
+}
 
 #[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
-pub struct BinaryOp(pub Uint128, pub Uint128);
+pub struct PublishFormulaMsg {
+    pub name: String,
+    pub version: u32,
+    pub description: String,
+    pub params: Vec<String>,
+    pub expr: String,
+    pub padding: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CellContent {
+    Value(Uint128),
+    // an expression over other cells, like those of formulas
+    Formula(String),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccumulatorOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl AccumulatorOp {
+    // the accumulator is always the left operand
+    pub fn with_operands(self, accumulator: Uint128, operand: Uint128) -> CalcOp {
+        let operands = BinaryOp(accumulator, operand);
+        match self {
+            AccumulatorOp::Add => CalcOp::Add(operands),
+            AccumulatorOp::Sub => CalcOp::Sub(operands),
+            AccumulatorOp::Mul => CalcOp::Mul(operands),
+            AccumulatorOp::Div => CalcOp::Div(operands),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ConfigUpdate {
+    pub admin: Option<HumanAddr>,
+    pub max_history: Option<u32>,
+    pub global_stats_enabled: Option<bool>,
+    pub max_batch_size: Option<u32>,
+    pub max_variables: Option<u32>,
+    pub privacy_mode: Option<bool>,
+    pub fee_denom: Option<String>,
+    // replaces the whole price table
+    pub prices: Option<BTreeMap<String, Uint128>>,
+    pub padding: Option<String>,
+}
+
+// `T` is the type of the operands. The shorthand operations and the `compute` query only take
+// literal values, `calculate` and `batch` also take references to previous calculations.
+#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CalcOp<T = Uint128> {
+    Add(BinaryOp<T>),
+    Sub(BinaryOp<T>),
+    Mul(BinaryOp<T>),
+    Div(BinaryOp<T>),
+    Sqrt(UnaryOp<T>),
+}
+
+impl<T> CalcOp<T> {
+    // the name of the operation as recorded in the history
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalcOp::Add(_) => "Add",
+            CalcOp::Sub(_) => "Sub",
+            CalcOp::Mul(_) => "Mul",
+            CalcOp::Div(_) => "Div",
+            CalcOp::Sqrt(_) => "Sqrt",
+        }
+    }
+
+    pub fn operands(&self) -> (&T, Option<&T>) {
+        match self {
+            CalcOp::Add(calculation)
+            | CalcOp::Sub(calculation)
+            | CalcOp::Mul(calculation)
+            | CalcOp::Div(calculation) => (&calculation.0, Some(&calculation.1)),
+            CalcOp::Sqrt(calculation) => (&calculation.0, None),
+        }
+    }
+
+    // the same operation, with each operand replaced by `f(operand)`
+    pub fn try_map<U, E, F: FnMut(T) -> Result<U, E>>(self, mut f: F) -> Result<CalcOp<U>, E> {
+        Ok(match self {
+            CalcOp::Add(BinaryOp(left, right)) => CalcOp::Add(BinaryOp(f(left)?, f(right)?)),
+            CalcOp::Sub(BinaryOp(left, right)) => CalcOp::Sub(BinaryOp(f(left)?, f(right)?)),
+            CalcOp::Mul(BinaryOp(left, right)) => CalcOp::Mul(BinaryOp(f(left)?, f(right)?)),
+            CalcOp::Div(BinaryOp(left, right)) => CalcOp::Div(BinaryOp(f(left)?, f(right)?)),
+            CalcOp::Sqrt(UnaryOp(radicand)) => CalcOp::Sqrt(UnaryOp(f(radicand)?)),
+        })
+    }
+}
+
+impl From<CalcOp> for CalcOp<Operand> {
+    fn from(op: CalcOp) -> Self {
+        match op.try_map::<_, Infallible, _>(|value| Ok(Operand::Value(value))) {
+            Ok(op) => op,
+            Err(never) => match never {},
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct UnaryOp<T = Uint128>(pub T);
+
+#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct BinaryOp<T = Uint128>(pub T, pub T);
+
+// References are resolved against the caller's own history, so calculations that weren't
+// recorded can't be referenced. `last` and `nth` skip the calculations that were undone.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Operand {
+    Value(Uint128),
+    // the result of the latest calculation
+    Last {},
+    // the result of the calculation with this id
+    ById(u64),
+    // the result of the calculation this many calculations before the latest one,
+    // so `nth: 0` is the same as `last`
+    Nth(u32),
+    // the value of the caller's variable with this name
+    Var(String),
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum CalculatorPermission {
     CalculationHistory,
+    Stats,
+    Variables,
+    Sheet,
+    Accumulator,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     WithPermit {
         permit: Permit<CalculatorPermission>,
         query: QueryWithPermit,
+        padding: Option<String>,
+    },
+    GlobalStats {
+        padding: Option<String>,
+    },
+    // runs an operation without recording anything
+    Compute {
+        op: CalcOp,
+        padding: Option<String>,
+    },
+    // public formulas, in the order they were published
+    ListFormulas {
+        page: Option<Uint128>,
+        page_size: Uint128,
+        padding: Option<String>,
+    },
+    Prices {
+        padding: Option<String>,
     },
 }
 
@@ -49,8 +551,27 @@ pub enum QueryWithPermit {
         page: Option<Uint128>,
         page_size: Uint128,
     },
+    Calculation {
+        id: u64,
+    },
+    Stats {},
+    Variables {},
+    Sheet {},
+    Accumulator {},
+    Session {
+        id: u64,
+        page: Option<Uint128>,
+        page_size: Uint128,
+    },
+    // most recently tagged first. `cursor` is the `next_cursor` of the previous page
+    ByTag {
+        tag: String,
+        cursor: Option<u64>,
+        limit: u32,
+    },
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
 #[cfg_attr(test, derive(Deserialize))]
 #[serde(rename_all = "snake_case")]
@@ -59,9 +580,220 @@ pub enum QueryAnswer {
         calcs: Vec<StoredCalculation>,
         total: Option<Uint128>,
     },
+    Calculation {
+        calc: StoredCalculation,
+    },
+    // counts every calculation of the account, including the ones that weren't recorded
+    Stats {
+        total: u64,
+        // number of calculations of each operation
+        operations: BTreeMap<String, u64>,
+        first_block_height: Option<u64>,
+        last_block_height: Option<u64>,
+        // only the failed operations of non-atomic batches. other failures revert the whole
+        // handle, along with anything it would have counted
+        failed_attempts: u64,
+    },
+    // the counters as of `block_height`, they are only updated every 100 blocks
+    GlobalStats {
+        total: u64,
+        operations: BTreeMap<String, u64>,
+        // number of accounts that made calculations
+        accounts: u64,
+        block_height: u64,
+        version: String,
+    },
+    Compute {
+        result: Uint128,
+    },
+    Variables {
+        variables: BTreeMap<String, Uint128>,
+    },
+    ListFormulas {
+        formulas: Vec<PublicFormulaInfo>,
+        total: u64,
+    },
+    Sheet {
+        cells: Vec<CellInfo>,
+    },
+    Accumulator {
+        value: Uint128,
+    },
+    Session {
+        session: Session,
+        // oldest first
+        calcs: Vec<StoredCalculation>,
+    },
+    ByTag {
+        calcs: Vec<StoredCalculation>,
+        next_cursor: Option<u64>,
+    },
+    Prices {
+        denom: String,
+        prices: BTreeMap<String, Uint128>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct CellInfo {
+    pub cell: String,
+    pub formula: Option<String>,
+    pub value: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PublicFormulaInfo {
+    pub author: HumanAddr,
+    pub name: String,
+    pub version: u32,
+    pub description: String,
+    pub deprecated: bool,
+    pub params: Vec<String>,
+    pub expr: String,
+}
+
+// Answer of the shorthand operations (`add`, `sub`, ...), which stay a bare number so that
+// existing clients keep working. `calculate` answers with a `HandleAnswer` instead.
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub struct LegacyHandleAnswer(pub Uint128);
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub struct HandleAnswer {
+    pub result: Uint128,
+    // id of the calculation in the history, if it was recorded
+    pub calculation_id: Option<u64>,
+    pub recorded: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub struct BatchAnswer {
+    // one item per operation, in the order they were given
+    pub results: Vec<BatchItemAnswer>,
+}
+
+// Either `result` or `error` is set. `calculation_id` is set only if the result was recorded.
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub struct BatchItemAnswer {
+    pub result: Option<Uint128>,
+    pub calculation_id: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryAnswer {
+    ClearHistory {
+        deleted: u32,
+    },
+    DeleteCalculation {
+        id: u64,
+    },
+    TagCalculation {
+        id: u64,
+        tags: Vec<String>,
+    },
+    Undo {
+        id: u64,
+    },
+    Redo {
+        id: u64,
+    },
+    SetRetention {
+        max_entries: u32,
+        ttl_blocks: Option<u64>,
+    },
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
 #[cfg_attr(test, derive(Deserialize))]
 #[serde(rename_all = "snake_case")]
-pub struct HandleAnswer(pub Uint128);
+pub enum ConfigAnswer {
+    UpdateConfig {
+        config: Config,
+    },
+    SetPreferences {
+        record_history: bool,
+        log: LogSettings,
+    },
+    // `remaining` is what's left of the fees in the withdrawn denom
+    WithdrawFees {
+        amount: Coin,
+        remaining: Uint128,
+    },
+    SetPaymentToken {
+        payment_token: PaymentToken,
+    },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum VariableAnswer {
+    SetVar { name: String, value: Uint128 },
+    IncrVar { name: String, value: Uint128 },
+    DecrVar { name: String, value: Uint128 },
+    ClearVar { name: String },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum AccumulatorAnswer {
+    Accumulate { previous: Uint128, value: Uint128 },
+    ResetAccumulator { previous: Uint128 },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum FormulaAnswer {
+    DefineFormula {
+        name: String,
+        params: Vec<String>,
+    },
+    PublishFormula {
+        name: String,
+        version: u32,
+    },
+    DeprecateFormula {
+        name: String,
+        version: u32,
+        deprecated: bool,
+    },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum SheetAnswer {
+    // the set cell and the cells whose value changed, in the order they were computed
+    SetCell { changed: Vec<CellInfo> },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum SessionAnswer {
+    StartSession { id: u64 },
+    EndSession { id: u64 },
+}
+
+// the hooks of the account after the change
+#[derive(Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum HookAnswer {
+    RegisterHook { hooks: Vec<Hook> },
+    UnregisterHook { hooks: Vec<Hook> },
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use cosmwasm_std::Uint128;
+
+use crate::error::{ContractError, ContractResult};
+use crate::formula::Expr;
+use crate::state::Cell;
+
+pub const MAX_SHEET_CELLS: usize = 256;
+// the most cells that setting a single cell may recompute, including itself
+pub const MAX_RECOMPUTED_CELLS: usize = 64;
+
+pub struct CellUpdate {
+    pub cell: String,
+    pub formula: Option<String>,
+    // the values of the referenced cells
+    pub args: Vec<Uint128>,
+    pub value: Uint128,
+}
+
+// Cells are addressed by column letters followed by a row number, e.g. `A1` or `AB12`.
+pub fn is_cell_name(name: &str) -> bool {
+    let row_start = match name.find(|c: char| c.is_ascii_digit()) {
+        Some(row_start) => row_start,
+        None => return false,
+    };
+    let (column, row) = name.split_at(row_start);
+
+    (1..=3).contains(&column.len())
+        && column.chars().all(|c| c.is_ascii_uppercase())
+        && (1..=5).contains(&row.len())
+        && row.chars().all(|c| c.is_ascii_digit())
+        && !row.starts_with('0')
+}
+
+// Sets `name` and recomputes the cells that depend on it, directly or not, so that every cell is
+// computed after the cells it references. Returns the set cell and the cells whose value changed,
+// in the order they were computed. Referencing an empty cell reads zero.
+pub fn update_cell(
+    sheet: &mut BTreeMap<String, Cell>,
+    name: &str,
+    cell: Cell,
+) -> ContractResult<Vec<CellUpdate>> {
+    if !sheet.contains_key(name) && sheet.len() >= MAX_SHEET_CELLS {
+        return Err(ContractError::TooManyCells {
+            max: MAX_SHEET_CELLS,
+        });
+    }
+    if depends_on(sheet, &cell.refs, name) {
+        return Err(ContractError::CyclicReference {
+            cell: name.to_string(),
+        });
+    }
+    sheet.insert(name.to_string(), cell);
+
+    let mut updates = vec![];
+    for cell_name in recompute_order(sheet, name)? {
+        let cell = &sheet[&cell_name];
+        let args: Vec<Uint128> = cell
+            .refs
+            .iter()
+            .map(|reference| {
+                sheet
+                    .get(reference)
+                    .map_or(Uint128::zero(), |cell| cell.value)
+            })
+            .collect();
+        let value = cell
+            .body
+            .evaluate(&args)
+            .map_err(|err| ContractError::CellFailed {
+                cell: cell_name.clone(),
+                error: Box::new(err),
+            })?;
+
+        let cell = sheet.get_mut(&cell_name).unwrap();
+        if cell_name == name || cell.value != value {
+            cell.value = value;
+            updates.push(CellUpdate {
+                formula: cell.formula.clone(),
+                cell: cell_name,
+                args,
+                value,
+            });
+        }
+    }
+
+    Ok(updates)
+}
+
+// Empties `name`, recomputing the cells that depend on it like `update_cell` does.
+pub fn clear_cell(
+    sheet: &mut BTreeMap<String, Cell>,
+    name: &str,
+) -> ContractResult<Vec<CellUpdate>> {
+    if !sheet.contains_key(name) {
+        return Ok(vec![]);
+    }
+
+    // an empty cell reads zero, so its dependents are recomputed as if it was zero
+    let empty = Cell {
+        formula: None,
+        body: Expr::Value(Uint128::zero()),
+        refs: vec![],
+        value: Uint128::zero(),
+    };
+    let updates = update_cell(sheet, name, empty)?;
+    sheet.remove(name);
+    Ok(updates)
+}
+
+// Whether any of `refs` is `target`, or references it through other cells.
+fn depends_on(sheet: &BTreeMap<String, Cell>, refs: &[String], target: &str) -> bool {
+    let mut visited = BTreeSet::new();
+    let mut pending: Vec<&String> = refs.iter().collect();
+    while let Some(reference) = pending.pop() {
+        if reference == target {
+            return true;
+        }
+        if visited.insert(reference) {
+            if let Some(cell) = sheet.get(reference) {
+                pending.extend(cell.refs.iter());
+            }
+        }
+    }
+    false
+}
+
+// `name` and the cells depending on it, in topological order. The sheet has no cycles, which is
+// checked whenever a cell is set.
+fn recompute_order(sheet: &BTreeMap<String, Cell>, name: &str) -> ContractResult<Vec<String>> {
+    let mut dependents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (cell_name, cell) in sheet {
+        for reference in &cell.refs {
+            dependents
+                .entry(reference.as_str())
+                .or_default()
+                .push(cell_name.as_str());
+        }
+    }
+
+    let mut affected = BTreeSet::new();
+    let mut pending = vec![name];
+    while let Some(cell_name) = pending.pop() {
+        if affected.insert(cell_name) {
+            if affected.len() > MAX_RECOMPUTED_CELLS {
+                return Err(ContractError::TooManyRecomputedCells {
+                    cell: name.to_string(),
+                    max: MAX_RECOMPUTED_CELLS,
+                });
+            }
+            if let Some(cell_dependents) = dependents.get(cell_name) {
+                pending.extend(cell_dependents.iter());
+            }
+        }
+    }
+
+    // the number of affected cells each affected cell is still waiting for
+    let mut waiting: BTreeMap<&str, usize> = affected
+        .iter()
+        .map(|&cell_name| {
+            let count = sheet[cell_name]
+                .refs
+                .iter()
+                .filter(|reference| affected.contains(reference.as_str()))
+                .count();
+            (cell_name, count)
+        })
+        .collect();
+
+    let mut order = vec![];
+    let mut ready = VecDeque::from(vec![name]);
+    while let Some(cell_name) = ready.pop_front() {
+        order.push(cell_name.to_string());
+        for &dependent in dependents.get(cell_name).into_iter().flatten() {
+            let count = waiting.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(dependent);
+            }
+        }
+    }
+
+    Ok(order)
+}
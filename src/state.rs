@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use cosmwasm_std::{HumanAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128};
 use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
 use schemars::JsonSchema;
@@ -5,8 +7,49 @@ use secret_toolkit::serialization::{Bincode2, Serde};
 use secret_toolkit::storage::{AppendStore, AppendStoreMut};
 use serde::{Deserialize, Serialize};
 
+use crate::error::{ContractError, ContractResult};
+use crate::formula::Expr;
+use crate::msg::{CellContent, LogSettings, Operand};
+
 pub static PREFIX_CALCULATIONS: &[u8] = b"calcs";
+pub static PREFIX_HISTORY_STATE: &[u8] = b"history";
+pub static PREFIX_RETENTION: &[u8] = b"retention";
+pub static PREFIX_PREFERENCES: &[u8] = b"preferences";
+pub static PREFIX_STATS: &[u8] = b"stats";
+pub static PREFIX_VARIABLES: &[u8] = b"variables";
+pub static PREFIX_FORMULAS: &[u8] = b"formulas";
+pub static PREFIX_PUBLIC_FORMULAS: &[u8] = b"public_formulas";
+pub static PREFIX_PUBLIC_FORMULA_INDEX: &[u8] = b"public_formula_index";
+pub static PREFIX_SHEETS: &[u8] = b"sheets";
+pub static PREFIX_SESSIONS: &[u8] = b"sessions";
+pub static PREFIX_SESSION_CALCULATIONS: &[u8] = b"session_calcs";
+pub static PREFIX_SESSION_POSITIONS: &[u8] = b"session_positions";
+pub static PREFIX_TAGS: &[u8] = b"tags";
+pub static PREFIX_TAG_POSITIONS: &[u8] = b"tag_positions";
+pub static PREFIX_REDO: &[u8] = b"redo";
+pub static PREFIX_ACCUMULATORS: &[u8] = b"accumulators";
+pub static PREFIX_HOOKS: &[u8] = b"hooks";
+pub static PREFIX_PAYMENT_TOKENS: &[u8] = b"payment_tokens";
 pub const KEY_CONSTANTS: &[u8] = b"constants";
+pub const KEY_CONFIG: &[u8] = b"config";
+pub const KEY_BLOCK_HEIGHT: &[u8] = b"block_height";
+pub const KEY_GLOBAL_STATS: &[u8] = b"global_stats";
+pub const KEY_PUBLISHED_GLOBAL_STATS: &[u8] = b"published_global_stats";
+pub const KEY_FEES: &[u8] = b"fees";
+
+pub const DEFAULT_MAX_HISTORY: u32 = 1000;
+pub const DEFAULT_MAX_BATCH_SIZE: u32 = 16;
+pub const DEFAULT_MAX_VARIABLES: u32 = 32;
+pub const DEFAULT_FEE_DENOM: &str = "uscrt";
+pub const GLOBAL_STATS_INTERVAL: u64 = 100;
+pub const MAX_VARIABLE_NAME_LENGTH: usize = 32;
+pub const MAX_FORMULA_NAME_LENGTH: usize = 32;
+pub const MAX_FORMULA_DESCRIPTION_LENGTH: usize = 256;
+pub const MAX_SESSION_LABEL_LENGTH: usize = 64;
+pub const MAX_MEMO_LENGTH: usize = 128;
+pub const MAX_TAGS: usize = 8;
+pub const MAX_TAG_LENGTH: usize = 32;
+pub const MAX_HOOKS: usize = 4;
 
 #[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Constants {
@@ -28,26 +71,1356 @@ pub fn get_constants<S: ReadonlyStorage>(storage: &S) -> StdResult<Constants> {
     }
 }
 
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Config {
+    pub admin: HumanAddr,
+    // maximum number of calculations kept in the history of each account
+    pub max_history: u32,
+    pub global_stats_enabled: bool,
+    // maximum number of operations in a single batch
+    pub max_batch_size: u32,
+    // maximum number of variables of each account
+    pub max_variables: u32,
+    // errors are replaced with the code of their kind, all padded to the same length
+    pub privacy_mode: bool,
+    pub fee_denom: String,
+    // the price of each action, by the name it logs as `action`. actions that aren't listed are
+    // free
+    pub prices: BTreeMap<String, Uint128>,
+    // the SNIP-20 token that can pay for actions too, at the same prices
+    pub payment_token: Option<PaymentToken>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PaymentToken {
+    pub contract_addr: HumanAddr,
+    pub code_hash: String,
+}
+
+pub fn set_config<S: Storage>(storage: &mut S, value: &Config) -> StdResult<()> {
+    storage.set(KEY_CONFIG, &Bincode2::serialize(value)?);
+    Ok(())
+}
+
+pub fn get_config<S: ReadonlyStorage>(storage: &S) -> StdResult<Config> {
+    match storage.get(KEY_CONFIG) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Err(StdError::NotFound {
+            kind: "Error getting config".to_string(),
+            backtrace: None,
+        }),
+    }
+}
+
+// The fees collected and not withdrawn yet, by denom. Fees paid in a token are under the address of
+// the token contract.
+pub fn set_fees<S: Storage>(storage: &mut S, value: &BTreeMap<String, Uint128>) -> StdResult<()> {
+    storage.set(KEY_FEES, &Bincode2::serialize(value)?);
+    Ok(())
+}
+
+pub fn get_fees<S: ReadonlyStorage>(storage: &S) -> StdResult<BTreeMap<String, Uint128>> {
+    match storage.get(KEY_FEES) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+// Queries don't get to see the current block, so the retention window of queries is computed
+// against the latest block height seen by `handle`.
+pub fn set_block_height<S: Storage>(storage: &mut S, height: u64) {
+    storage.set(KEY_BLOCK_HEIGHT, &height.to_be_bytes());
+}
+
+pub fn get_block_height<S: ReadonlyStorage>(storage: &S) -> u64 {
+    let mut height = [0u8; 8];
+    match storage.get(KEY_BLOCK_HEIGHT) {
+        Some(value) if value.len() == height.len() => {
+            height.copy_from_slice(&value);
+            u64::from_be_bytes(height)
+        }
+        _ => 0,
+    }
+}
+
+// Every token that was ever the payment token, by address, so that the fees paid in it can still be
+// withdrawn after it's replaced. Registering a token again updates its code hash.
+pub fn add_payment_token<S: Storage>(storage: &mut S, token: &PaymentToken) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_PAYMENT_TOKENS, storage);
+    store.set(
+        token.contract_addr.as_str().as_bytes(),
+        &Bincode2::serialize(token)?,
+    );
+    Ok(())
+}
+
+pub fn get_payment_token<S: ReadonlyStorage>(
+    storage: &S,
+    contract_addr: &HumanAddr,
+) -> StdResult<Option<PaymentToken>> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_PAYMENT_TOKENS, storage);
+    match store.get(contract_addr.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Retention {
+    // can only lower the maximum set by the admin
+    pub max_entries: Option<u32>,
+    pub ttl_blocks: Option<u64>,
+}
+
+pub fn set_retention<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &Retention,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_RETENTION, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+pub fn get_retention<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<Retention> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_RETENTION, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(Retention::default()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Preferences {
+    pub record_history: bool,
+    pub log: LogSettings,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            record_history: true,
+            log: LogSettings::default(),
+        }
+    }
+}
+
+pub fn set_preferences<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &Preferences,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_PREFERENCES, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+pub fn get_preferences<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<Preferences> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_PREFERENCES, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(Preferences::default()),
+    }
+}
+
+// The variables of an account are few and always read together, so they are stored as a single
+// entry rather than one entry per variable.
+pub fn set_variables<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &BTreeMap<String, Uint128>,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_VARIABLES, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+pub fn get_variables<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<BTreeMap<String, Uint128>> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_VARIABLES, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+pub fn set_accumulator<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: Uint128,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_ACCUMULATORS, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(&value)?,
+    );
+    Ok(())
+}
+
+pub fn get_accumulator<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<Uint128> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_ACCUMULATORS, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(Uint128::zero()),
+    }
+}
+
+// A contract notified of each calculation of the account that registered it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Hook {
+    pub contract_addr: HumanAddr,
+    pub code_hash: String,
+}
+
+pub fn set_hooks<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &[Hook],
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_HOOKS, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(&value)?,
+    );
+    Ok(())
+}
+
+pub fn get_hooks<S: ReadonlyStorage>(storage: &S, for_address: &HumanAddr) -> StdResult<Vec<Hook>> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_HOOKS, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(vec![]),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Formula {
+    pub params: Vec<String>,
+    // the expression as it was defined
+    pub expr: String,
+    pub body: Expr,
+}
+
+pub fn set_formula<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    name: &str,
+    value: &Formula,
+) -> StdResult<()> {
+    let mut store =
+        PrefixedStorage::multilevel(&[PREFIX_FORMULAS, for_address.as_str().as_bytes()], storage);
+    store.set(name.as_bytes(), &Bincode2::serialize(value)?);
+    Ok(())
+}
+
+pub fn get_formula<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    name: &str,
+) -> StdResult<Option<Formula>> {
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_FORMULAS, for_address.as_str().as_bytes()],
+        storage,
+    );
+    store
+        .get(name.as_bytes())
+        .map(|value| Bincode2::deserialize(&value))
+        .transpose()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PublicFormula {
+    pub author: HumanAddr,
+    pub name: String,
+    pub version: u32,
+    pub description: String,
+    pub deprecated: bool,
+    pub formula: Formula,
+}
+
+// Public formulas are kept in publication order so they can be listed, with an index from
+// (author, name, version) to their position.
+fn public_formula_position<S: ReadonlyStorage>(
+    storage: &S,
+    author: &HumanAddr,
+    name: &str,
+    version: u32,
+) -> StdResult<Option<u32>> {
+    let index = ReadonlyPrefixedStorage::multilevel(
+        &[
+            PREFIX_PUBLIC_FORMULA_INDEX,
+            author.as_str().as_bytes(),
+            name.as_bytes(),
+        ],
+        storage,
+    );
+    index
+        .get(&version.to_be_bytes())
+        .map(|value| Bincode2::deserialize(&value))
+        .transpose()
+}
+
+pub fn publish_formula<S: Storage>(storage: &mut S, value: &PublicFormula) -> ContractResult<()> {
+    if public_formula_position(&*storage, &value.author, &value.name, value.version)?.is_some() {
+        return Err(ContractError::FormulaVersionExists {
+            name: value.name.clone(),
+            version: value.version,
+        });
+    }
+
+    let mut store = PrefixedStorage::new(PREFIX_PUBLIC_FORMULAS, storage);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    let position = store.len();
+    store.push(value)?;
+
+    let mut index = PrefixedStorage::multilevel(
+        &[
+            PREFIX_PUBLIC_FORMULA_INDEX,
+            value.author.as_str().as_bytes(),
+            value.name.as_bytes(),
+        ],
+        storage,
+    );
+    index.set(
+        &value.version.to_be_bytes(),
+        &Bincode2::serialize(&position)?,
+    );
+    Ok(())
+}
+
+pub fn get_public_formula<S: ReadonlyStorage>(
+    storage: &S,
+    author: &HumanAddr,
+    name: &str,
+    version: u32,
+) -> StdResult<Option<PublicFormula>> {
+    let position = match public_formula_position(storage, author, name, version)? {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+
+    let store = ReadonlyPrefixedStorage::new(PREFIX_PUBLIC_FORMULAS, storage);
+    match AppendStore::<PublicFormula, _, _>::attach(&store) {
+        Some(store) => store?.get_at(position).map(Some),
+        None => Ok(None),
+    }
+}
+
+// The only part of a published formula that can change.
+pub fn set_public_formula_deprecated<S: Storage>(
+    storage: &mut S,
+    author: &HumanAddr,
+    name: &str,
+    version: u32,
+    deprecated: bool,
+) -> ContractResult<()> {
+    let not_found = || ContractError::FormulaVersionNotFound {
+        name: name.to_string(),
+        version,
+    };
+    let position =
+        public_formula_position(&*storage, author, name, version)?.ok_or_else(not_found)?;
+
+    let mut store = PrefixedStorage::new(PREFIX_PUBLIC_FORMULAS, storage);
+    let mut store = match AppendStoreMut::<PublicFormula, _>::attach(&mut store) {
+        Some(store) => store?,
+        None => return Err(not_found()),
+    };
+    let mut formula = store.get_at(position)?;
+    formula.deprecated = deprecated;
+    store.set_at(position, &formula)?;
+    Ok(())
+}
+
+pub fn get_public_formulas<S: ReadonlyStorage>(
+    storage: &S,
+    page: Uint128,
+    page_size: Uint128,
+) -> StdResult<(Vec<PublicFormula>, u64)> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_PUBLIC_FORMULAS, storage);
+    let store = match AppendStore::<PublicFormula, _, _>::attach(&store) {
+        Some(store) => store?,
+        None => return Ok((vec![], 0)),
+    };
+
+    let formulas: StdResult<Vec<PublicFormula>> = store
+        .iter()
+        .skip((page.u128() * page_size.u128()) as _)
+        .take(page_size.u128() as _)
+        .collect();
+
+    formulas.map(|formulas| (formulas, store.len() as u64))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cell {
+    // the formula as it was set, `None` for literal values
+    pub formula: Option<String>,
+    pub body: Expr,
+    // the cells referenced by the formula, in the order `body` expects their values
+    pub refs: Vec<String>,
+    pub value: Uint128,
+}
+
+// Like variables, the cells of an account are stored as a single entry since setting a cell
+// needs the whole dependency graph anyway.
+pub fn set_sheet<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &BTreeMap<String, Cell>,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_SHEETS, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+pub fn get_sheet<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<BTreeMap<String, Cell>> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_SHEETS, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Session {
+    pub id: u64,
+    pub label: String,
+    pub start_block_height: u64,
+    pub end_block_height: Option<u64>,
+}
+
+// The sessions of an account, with their position as id.
+pub fn start_session<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    label: String,
+    block_height: u64,
+) -> StdResult<u64> {
+    let mut store =
+        PrefixedStorage::multilevel(&[PREFIX_SESSIONS, for_address.as_str().as_bytes()], storage);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+
+    let id = store.len() as u64;
+    store.push(&Session {
+        id,
+        label,
+        start_block_height: block_height,
+        end_block_height: None,
+    })?;
+    Ok(id)
+}
+
+pub fn end_session<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    id: u64,
+    block_height: u64,
+) -> ContractResult<()> {
+    let mut session =
+        get_session(&*storage, for_address, id)?.ok_or(ContractError::SessionNotFound { id })?;
+    if session.end_block_height.is_some() {
+        return Err(ContractError::SessionEnded { id });
+    }
+    session.end_block_height = Some(block_height);
+
+    let mut store =
+        PrefixedStorage::multilevel(&[PREFIX_SESSIONS, for_address.as_str().as_bytes()], storage);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.set_at(id as u32, &session)?;
+    Ok(())
+}
+
+pub fn get_session<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    id: u64,
+) -> StdResult<Option<Session>> {
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_SESSIONS, for_address.as_str().as_bytes()],
+        storage,
+    );
+    let store = match AppendStore::<Session, _, _>::attach(&store) {
+        Some(store) => store?,
+        None => return Ok(None),
+    };
+    if id >= store.len() as u64 {
+        return Ok(None);
+    }
+    store.get_at(id as u32).map(Some)
+}
+
+// Lists of calculation ids, like those of a session or a tag, are kept in the order the ids were
+// added, with tombstones for the ones that were removed since. The position of each id in its list
+// is stored apart, so it can be removed without a scan.
+fn push_listed_id<S: Storage>(
+    storage: &mut S,
+    list: &[&[u8]],
+    positions: &[&[u8]],
+    id: u64,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::multilevel(list, storage);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    let position = store.len();
+    store.push(&Some(id))?;
+
+    let mut positions = PrefixedStorage::multilevel(positions, storage);
+    positions.set(&id.to_be_bytes(), &Bincode2::serialize(&position)?);
+    Ok(())
+}
+
+// Tombstones at the end of the list are dropped, so that a list whose ids were all removed is
+// empty again.
+fn remove_listed_id<S: Storage>(
+    storage: &mut S,
+    list: &[&[u8]],
+    positions: &[&[u8]],
+    id: u64,
+) -> StdResult<()> {
+    let mut positions = PrefixedStorage::multilevel(positions, storage);
+    let position: u32 = match positions.get(&id.to_be_bytes()) {
+        Some(value) => Bincode2::deserialize(&value)?,
+        None => return Ok(()),
+    };
+    positions.remove(&id.to_be_bytes());
+
+    let mut store = PrefixedStorage::multilevel(list, storage);
+    let mut store = AppendStoreMut::<Option<u64>, _>::attach_or_create(&mut store)?;
+    store.set_at(position, &None)?;
+    while let Some(last) = store.len().checked_sub(1) {
+        if store.get_at(last)?.is_some() {
+            break;
+        }
+        store.pop()?;
+    }
+    Ok(())
+}
+
+pub fn add_session_calculation<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    session: u64,
+    calculation_id: u64,
+) -> StdResult<()> {
+    let session = session.to_be_bytes();
+    push_listed_id(
+        storage,
+        &[
+            PREFIX_SESSION_CALCULATIONS,
+            for_address.as_str().as_bytes(),
+            &session,
+        ],
+        &[
+            PREFIX_SESSION_POSITIONS,
+            for_address.as_str().as_bytes(),
+            &session,
+        ],
+        calculation_id,
+    )
+}
+
+fn remove_session_calculation<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    session: u64,
+    calculation_id: u64,
+) -> StdResult<()> {
+    let session = session.to_be_bytes();
+    remove_listed_id(
+        storage,
+        &[
+            PREFIX_SESSION_CALCULATIONS,
+            for_address.as_str().as_bytes(),
+            &session,
+        ],
+        &[
+            PREFIX_SESSION_POSITIONS,
+            for_address.as_str().as_bytes(),
+            &session,
+        ],
+        calculation_id,
+    )
+}
+
+// The calculations of a session, oldest first. Calculations that expired since are skipped.
+pub fn get_session_calculations<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    session: u64,
+    page: Uint128,
+    page_size: Uint128,
+) -> StdResult<Vec<StoredCalculation>> {
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[
+            PREFIX_SESSION_CALCULATIONS,
+            for_address.as_str().as_bytes(),
+            &session.to_be_bytes(),
+        ],
+        storage,
+    );
+    let store = match AppendStore::<Option<u64>, _, _>::attach(&store) {
+        Some(store) => store?,
+        None => return Ok(vec![]),
+    };
+
+    store
+        .iter()
+        .filter_map(|id| id.transpose())
+        .map(|id| get_calculation(storage, for_address, id?))
+        .filter_map(|calculation| calculation.transpose())
+        .skip((page.u128() * page_size.u128()) as _)
+        .take(page_size.u128() as _)
+        .collect()
+}
+
+// The calculations with a tag are kept in the order they were tagged.
+pub fn add_tag<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    tag: &str,
+    calculation_id: u64,
+) -> StdResult<()> {
+    push_listed_id(
+        storage,
+        &[PREFIX_TAGS, for_address.as_str().as_bytes(), tag.as_bytes()],
+        &[
+            PREFIX_TAG_POSITIONS,
+            for_address.as_str().as_bytes(),
+            tag.as_bytes(),
+        ],
+        calculation_id,
+    )
+}
+
+pub fn remove_tag<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    tag: &str,
+    calculation_id: u64,
+) -> StdResult<()> {
+    remove_listed_id(
+        storage,
+        &[PREFIX_TAGS, for_address.as_str().as_bytes(), tag.as_bytes()],
+        &[
+            PREFIX_TAG_POSITIONS,
+            for_address.as_str().as_bytes(),
+            tag.as_bytes(),
+        ],
+        calculation_id,
+    )
+}
+
+// Calculations with `tag`, most recently tagged first, starting before `cursor`. Returns a cursor
+// for the next page if there may be more.
+pub fn get_tagged_calculations<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    tag: &str,
+    cursor: Option<u64>,
+    limit: u32,
+) -> StdResult<(Vec<StoredCalculation>, Option<u64>)> {
+    let namespace = [PREFIX_TAGS, for_address.as_str().as_bytes(), tag.as_bytes()];
+    let store = ReadonlyPrefixedStorage::multilevel(&namespace, storage);
+    let store = match AppendStore::<Option<u64>, _, _>::attach(&store) {
+        Some(store) => store?,
+        None => return Ok((vec![], None)),
+    };
+
+    let end = cursor.map_or(store.len(), |cursor| cursor.min(store.len() as u64) as u32);
+    let mut calculations = vec![];
+    let mut position = end;
+    while position > 0 && calculations.len() < limit as usize {
+        position -= 1;
+        let id = match store.get_at(position)? {
+            Some(id) => id,
+            None => continue,
+        };
+        // calculations that were deleted or fell out of the history are skipped
+        if let Some(calculation) = get_calculation(storage, for_address, id)? {
+            calculations.push(calculation);
+        }
+    }
+
+    let next_cursor = if position > 0 {
+        Some(position as u64)
+    } else {
+        None
+    };
+    Ok((calculations, next_cursor))
+}
+
+// The ids of the calculations that were undone, most recently undone last. Like variables, it is
+// stored as a single entry.
+pub fn set_redo_stack<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &[u64],
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_REDO, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(&value)?,
+    );
+    Ok(())
+}
+
+pub fn get_redo_stack<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<Vec<u64>> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_REDO, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(vec![]),
+    }
+}
+
+// Usage counters of an account, kept apart from its history so they don't need to be recomputed,
+// and so they aren't affected by deletions or retention.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AccountStats {
+    pub total: u64,
+    pub operations: BTreeMap<String, u64>,
+    pub first_block_height: Option<u64>,
+    pub last_block_height: Option<u64>,
+    // Failed handles revert their storage writes, so only failures that don't abort the
+    // transaction can be counted. These are the failed operations of non-atomic batches.
+    pub failed_attempts: u64,
+}
+
+impl AccountStats {
+    pub fn add_calculation(&mut self, operation: &str, block_height: u64) {
+        self.total += 1;
+        *self.operations.entry(operation.to_string()).or_insert(0) += 1;
+        self.first_block_height.get_or_insert(block_height);
+        self.last_block_height = Some(block_height);
+    }
+
+    pub fn add_failure(&mut self) {
+        self.failed_attempts += 1;
+    }
+}
+
+pub fn set_account_stats<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &AccountStats,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_STATS, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+pub fn get_account_stats<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<AccountStats> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_STATS, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(AccountStats::default()),
+    }
+}
+
+// Aggregated over all accounts, nothing in here may identify a single account.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GlobalStats {
+    pub total: u64,
+    pub operations: BTreeMap<String, u64>,
+    pub accounts: u64,
+}
+
+impl GlobalStats {
+    pub fn add_calculation(&mut self, operation: &str, new_account: bool) {
+        self.total += 1;
+        *self.operations.entry(operation.to_string()).or_insert(0) += 1;
+        if new_account {
+            self.accounts += 1;
+        }
+    }
+}
+
+pub fn set_global_stats<S: Storage>(storage: &mut S, value: &GlobalStats) -> StdResult<()> {
+    storage.set(KEY_GLOBAL_STATS, &Bincode2::serialize(value)?);
+    Ok(())
+}
+
+pub fn get_global_stats<S: ReadonlyStorage>(storage: &S) -> StdResult<GlobalStats> {
+    match storage.get(KEY_GLOBAL_STATS) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(GlobalStats::default()),
+    }
+}
+
+// The global stats as of `block_height`. Diffing live counters before and after a transaction
+// would tell what it calculated and whether its account is new, so only a copy taken at most once
+// every `GLOBAL_STATS_INTERVAL` blocks is public.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PublishedGlobalStats {
+    pub stats: GlobalStats,
+    pub block_height: u64,
+}
+
+pub fn get_published_global_stats<S: ReadonlyStorage>(
+    storage: &S,
+) -> StdResult<PublishedGlobalStats> {
+    match storage.get(KEY_PUBLISHED_GLOBAL_STATS) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(PublishedGlobalStats::default()),
+    }
+}
+
+/// Copies the live global stats to the public ones, once the interval since the last copy passed.
+pub fn publish_global_stats<S: Storage>(storage: &mut S, block_height: u64) -> StdResult<()> {
+    let published = get_published_global_stats(storage)?;
+    if block_height < published.block_height + GLOBAL_STATS_INTERVAL {
+        return Ok(());
+    }
+
+    let published = PublishedGlobalStats {
+        stats: get_global_stats(storage)?,
+        block_height,
+    };
+    storage.set(
+        KEY_PUBLISHED_GLOBAL_STATS,
+        &Bincode2::serialize(&published)?,
+    );
+    Ok(())
+}
+
+// The retention window that applies to an account, combining the admin's maximum with the
+// account's own settings.
+fn get_retention_window<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<Retention> {
+    let max_history = get_config(storage)?.max_history;
+    let retention = get_retention(storage, for_address)?;
+
+    Ok(Retention {
+        max_entries: Some(
+            retention
+                .max_entries
+                .map_or(max_history, |max_entries| max_entries.min(max_history)),
+        ),
+        ttl_blocks: retention.ttl_blocks,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct FormulaCall {
+    // set if a public formula was invoked
+    pub author: Option<HumanAddr>,
+    pub name: String,
+    pub version: Option<u32>,
+    pub args: Vec<Uint128>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct StoredCalculation {
+    pub id: u64,
     pub left_operand: Uint128,
     pub right_operand: Option<Uint128>,
+    // the references the operands were resolved from, if they weren't given as values
+    pub left_reference: Option<Operand>,
+    pub right_reference: Option<Operand>,
+    // set for formula invocations, which have no operands of their own. their
+    // `left_operand` is zero
+    pub formula: Option<FormulaCall>,
+    pub session: Option<u64>,
+    pub memo: Option<String>,
+    pub tags: Vec<String>,
+    pub effect: Option<Effect>,
+    pub undone: bool,
     pub operation: String,
     pub result: Uint128,
+    pub block_height: u64,
+}
+
+// What a calculation changed besides the history, so that undoing it can change it back.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    // `None` if the variable wasn't set
+    Variable {
+        name: String,
+        previous: Option<Uint128>,
+        value: Option<Uint128>,
+    },
+    // `None` if the cell was empty. The effect is on the last record of the cells that were
+    // recomputed, and `records` is how many of them were recorded right before it
+    Cell {
+        cell: String,
+        previous: Option<CellContent>,
+        content: CellContent,
+        records: u32,
+    },
+    Accumulator {
+        previous: Uint128,
+        value: Uint128,
+    },
+}
+
+impl StoredCalculation {
+    fn is_expired(&self, retention: &Retention, block_height: u64) -> bool {
+        match retention.ttl_blocks {
+            Some(ttl) => self.block_height.saturating_add(ttl) < block_height,
+            None => false,
+        }
+    }
+}
+
+/// The history of an account is a ring buffer on top of an `AppendStore` of slots. Slots are
+/// only appended while the retention window has room for more entries; after that, the slot of
+/// the oldest entry gets overwritten. Entries that are deleted or fall out of the retention window
+/// are overwritten with `None` tombstones.
+///
+/// The entries in the window are the ids `first_id..next_id`, and `head` is the position of
+/// `first_id` in the ring. Ids are never reused.
+///
+/// Positions in the ring are usually the slots themselves. When the ring has to grow while it
+/// wraps around, the new slots are appended to the store but belong after the newest entry, so
+/// `layout` records the runs of slots in ring order instead of moving the existing entries.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HistoryState {
+    pub first_id: u64,
+    pub next_id: u64,
+    pub head: u32,
+    // number of entries in the window that weren't deleted
+    pub live: u32,
+    // (first slot, number of slots) of each run in ring order, empty when it's the store order
+    pub layout: Vec<(u32, u32)>,
+}
+
+impl HistoryState {
+    fn len(&self) -> u64 {
+        self.next_id - self.first_id
+    }
+
+    fn slot(&self, id: u64, slots: u32) -> u32 {
+        let position = (self.head as u64 + (id - self.first_id)) % slots as u64;
+        self.slot_at(position as u32)
+    }
+
+    fn slot_at(&self, mut position: u32) -> u32 {
+        for &(first, count) in &self.layout {
+            if position < count {
+                return first + position;
+            }
+            position -= count;
+        }
+        position
+    }
+
+    // Puts the slot appended after the last one of the store at `position` in the ring.
+    fn insert_slot(&mut self, position: u32, slots: u32) {
+        if self.layout.is_empty() {
+            if position == slots {
+                return;
+            }
+            self.layout.push((0, slots));
+        }
+
+        let mut start = 0;
+        let mut index = 0;
+        while index < self.layout.len() && start + self.layout[index].1 <= position {
+            start += self.layout[index].1;
+            index += 1;
+        }
+        if start < position {
+            let (first, count) = self.layout[index];
+            let before = position - start;
+            self.layout[index] = (first, before);
+            self.layout
+                .insert(index + 1, (first + before, count - before));
+            index += 1;
+        }
+
+        match index
+            .checked_sub(1)
+            .map(|previous| &mut self.layout[previous])
+        {
+            Some((first, count)) if *first + *count == slots => *count += 1,
+            _ => self.layout.insert(index, (slots, 1)),
+        }
+    }
 }
 
+fn get_history_state<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+) -> StdResult<HistoryState> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_HISTORY_STATE, storage);
+    match store.get(for_address.as_str().as_bytes()) {
+        Some(value) => Bincode2::deserialize(&value),
+        None => Ok(HistoryState::default()),
+    }
+}
+
+fn set_history_state<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    value: &HistoryState,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_HISTORY_STATE, storage);
+    store.set(
+        for_address.as_str().as_bytes(),
+        &Bincode2::serialize(value)?,
+    );
+    Ok(())
+}
+
+// Finds the oldest id that is still inside the retention window, leaving room for `reserve` new
+// entries. Also returns how many live entries are older than it.
+// Entries are ordered by block height, so expired entries are always the oldest ones.
+fn find_retention_start<S: ReadonlyStorage>(
+    store: &AppendStore<Option<StoredCalculation>, S>,
+    state: &HistoryState,
+    retention: &Retention,
+    block_height: u64,
+    reserve: u64,
+) -> StdResult<(u64, u32)> {
+    let max_entries = retention.max_entries.unwrap_or(u32::MAX) as u64;
+
+    let mut id = state.first_id;
+    let mut dropped = 0;
+    while id < state.next_id {
+        if let Some(calculation) = store.get_at(state.slot(id, store.len()))? {
+            let in_capacity = state.next_id - id + reserve <= max_entries;
+            if in_capacity && !calculation.is_expired(retention, block_height) {
+                break;
+            }
+            dropped += 1;
+        }
+        id += 1;
+    }
+
+    Ok((id, dropped))
+}
+
+// Overwrites the entries older than `id` with tombstones, and moves the window past them. Returns
+// the entries that were evicted, which still have to be removed from the indexes.
+fn evict_before<S: Storage>(
+    store: &mut AppendStoreMut<Option<StoredCalculation>, S>,
+    state: &mut HistoryState,
+    id: u64,
+) -> StdResult<Vec<StoredCalculation>> {
+    let mut evicted = vec![];
+    while state.first_id < id {
+        let slot = state.slot_at(state.head);
+        if let Some(calculation) = store.get_at(slot)? {
+            store.set_at(slot, &None)?;
+            state.live -= 1;
+            evicted.push(calculation);
+        }
+        state.first_id += 1;
+        state.head = (state.head + 1) % store.len();
+    }
+
+    // an empty window can start over from the first slot, in store order
+    if state.len() == 0 {
+        state.head = 0;
+        state.layout.clear();
+    }
+    Ok(evicted)
+}
+
+// Removes calculations that left the history from their session and tags.
+fn unindex_calculations<S: Storage>(
+    storage: &mut S,
+    for_address: &HumanAddr,
+    calculations: &[StoredCalculation],
+) -> StdResult<()> {
+    for calculation in calculations {
+        if let Some(session) = calculation.session {
+            remove_session_calculation(storage, for_address, session, calculation.id)?;
+        }
+        for tag in &calculation.tags {
+            remove_tag(storage, for_address, tag, calculation.id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends a calculation to the account's history, assigning it the next id.
+/// Entries that don't fit the retention window anymore are evicted first.
 pub fn append_calculation<S: Storage>(
     store: &mut S,
-    calculation: &StoredCalculation,
+    mut calculation: StoredCalculation,
+    for_address: &HumanAddr,
+) -> StdResult<u64> {
+    let retention = get_retention_window(store, for_address)?;
+    let mut state = get_history_state(store, for_address)?;
+
+    let mut calcs_store = PrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        store,
+    );
+    let mut calcs_store = AppendStoreMut::attach_or_create(&mut calcs_store)?;
+
+    let (start, _) = find_retention_start(
+        &calcs_store.as_readonly(),
+        &state,
+        &retention,
+        calculation.block_height,
+        1,
+    )?;
+    let evicted = evict_before(&mut calcs_store, &mut state, start)?;
+
+    calculation.id = state.next_id;
+    if state.len() < calcs_store.len() as u64 {
+        let slot = state.slot(calculation.id, calcs_store.len());
+        calcs_store.set_at(slot, &Some(calculation))?;
+    } else {
+        // The new slot goes between the newest and the oldest entry. When the ring wraps around,
+        // that's in the middle of the store, and the oldest entry moves one position further.
+        let slots = calcs_store.len();
+        if state.head == 0 {
+            state.insert_slot(slots, slots);
+        } else {
+            state.insert_slot(state.head, slots);
+            state.head += 1;
+        }
+        calcs_store.push(&Some(calculation))?;
+    }
+
+    state.next_id += 1;
+    state.live += 1;
+    set_history_state(store, for_address, &state)?;
+    unindex_calculations(store, for_address, &evicted)?;
+    Ok(state.next_id - 1)
+}
+
+/// Evicts the entries of the account that fell out of its retention window.
+pub fn apply_retention<S: Storage>(
+    store: &mut S,
     for_address: &HumanAddr,
+    block_height: u64,
 ) -> StdResult<()> {
-    let mut store = PrefixedStorage::multilevel(
+    let retention = get_retention_window(store, for_address)?;
+    let mut state = get_history_state(store, for_address)?;
+
+    let mut calcs_store = PrefixedStorage::multilevel(
         &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
         store,
     );
-    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
-    store.push(calculation)
+    let mut calcs_store =
+        match AppendStoreMut::<Option<StoredCalculation>, _>::attach(&mut calcs_store) {
+            Some(result) => result?,
+            None => return Ok(()),
+        };
+
+    let (start, _) = find_retention_start(
+        &calcs_store.as_readonly(),
+        &state,
+        &retention,
+        block_height,
+        0,
+    )?;
+    let evicted = evict_before(&mut calcs_store, &mut state, start)?;
+
+    set_history_state(store, for_address, &state)?;
+    unindex_calculations(store, for_address, &evicted)
+}
+
+/// Overwrites a calculation with a tombstone, erasing its contents from storage.
+pub fn delete_calculation<S: Storage>(
+    store: &mut S,
+    for_address: &HumanAddr,
+    id: u64,
+) -> ContractResult<()> {
+    let mut state = get_history_state(store, for_address)?;
+
+    let mut calcs_store = PrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        store,
+    );
+    let mut calcs_store =
+        match AppendStoreMut::<Option<StoredCalculation>, _>::attach(&mut calcs_store) {
+            Some(result) => result?,
+            None => return Err(ContractError::CalculationNotFound { id }),
+        };
+
+    let in_window = id >= state.first_id && id < state.next_id;
+    let slot = if in_window {
+        Some(state.slot(id, calcs_store.len()))
+    } else {
+        None
+    };
+    let deleted = match slot {
+        Some(slot) => calcs_store
+            .get_at(slot)?
+            .map(|calculation| (slot, calculation)),
+        None => None,
+    };
+    let (slot, calculation) = deleted.ok_or(ContractError::CalculationNotFound { id })?;
+    calcs_store.set_at(slot, &None)?;
+
+    state.live -= 1;
+    set_history_state(store, for_address, &state)?;
+    unindex_calculations(store, for_address, &[calculation])?;
+    Ok(())
+}
+
+/// Overwrites a live calculation, which keeps its id.
+pub fn replace_calculation<S: Storage>(
+    store: &mut S,
+    for_address: &HumanAddr,
+    calculation: &StoredCalculation,
+) -> ContractResult<()> {
+    let not_found = || ContractError::CalculationNotFound { id: calculation.id };
+    if get_calculation(&*store, for_address, calculation.id)?.is_none() {
+        return Err(not_found());
+    }
+    let state = get_history_state(store, for_address)?;
+
+    let mut calcs_store = PrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        store,
+    );
+    let mut calcs_store =
+        match AppendStoreMut::<Option<StoredCalculation>, _>::attach(&mut calcs_store) {
+            Some(result) => result?,
+            None => return Err(not_found()),
+        };
+
+    let slot = state.slot(calculation.id, calcs_store.len());
+    calcs_store.set_at(slot, &Some(calculation.clone()))?;
+    Ok(())
+}
+
+/// Overwrites every live calculation of the account with a tombstone.
+/// Returns the number of calculations that were deleted.
+pub fn clear_calculations<S: Storage>(store: &mut S, for_address: &HumanAddr) -> StdResult<u32> {
+    let mut state = get_history_state(store, for_address)?;
+    let deleted = state.live;
+
+    let mut calcs_store = PrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        store,
+    );
+    let mut calcs_store =
+        match AppendStoreMut::<Option<StoredCalculation>, _>::attach(&mut calcs_store) {
+            Some(result) => result?,
+            None => return Ok(0),
+        };
+
+    let next_id = state.next_id;
+    let evicted = evict_before(&mut calcs_store, &mut state, next_id)?;
+
+    set_history_state(store, for_address, &state)?;
+    unindex_calculations(store, for_address, &evicted)?;
+    Ok(deleted)
+}
+
+/// Looks up a single calculation by its id, which maps directly to its slot in the store.
+pub fn get_calculation<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    id: u64,
+) -> StdResult<Option<StoredCalculation>> {
+    let retention = get_retention_window(storage, for_address)?;
+    let state = get_history_state(storage, for_address)?;
+
+    let max_entries = retention.max_entries.unwrap_or(u32::MAX) as u64;
+    if id < state.first_id || id >= state.next_id || state.next_id - id > max_entries {
+        return Ok(None);
+    }
+
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        storage,
+    );
+    let store = match AppendStore::<Option<StoredCalculation>, _, _>::attach(&store) {
+        Some(result) => result?,
+        None => return Ok(None),
+    };
+
+    let calculation = store.get_at(state.slot(id, store.len()))?;
+    Ok(calculation
+        .filter(|calculation| !calculation.is_expired(&retention, get_block_height(storage))))
+}
+
+/// The calculation `back` calculations before the latest one that isn't undone, skipping undone
+/// calculations too. Undone calculations are kept in the history, so `back: 0` may be older than
+/// the latest one.
+pub fn get_active_calculation<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    back: u32,
+) -> StdResult<Option<StoredCalculation>> {
+    let retention = get_retention_window(storage, for_address)?;
+    let state = get_history_state(storage, for_address)?;
+
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
+        storage,
+    );
+    let store = match AppendStore::<Option<StoredCalculation>, _, _>::attach(&store) {
+        Some(result) => result?,
+        None => return Ok(None),
+    };
+
+    let max_entries = retention.max_entries.unwrap_or(u32::MAX) as u64;
+    let oldest = state
+        .first_id
+        .max(state.next_id.saturating_sub(max_entries));
+    let block_height = get_block_height(storage);
+    let mut back = back;
+    for id in (oldest..state.next_id).rev() {
+        let calculation = match store.get_at(state.slot(id, store.len()))? {
+            Some(calculation) => calculation,
+            None => continue,
+        };
+        // the entries before an expired one are expired too
+        if calculation.is_expired(&retention, block_height) {
+            return Ok(None);
+        }
+        if calculation.undone {
+            continue;
+        }
+        match back.checked_sub(1) {
+            Some(remaining) => back = remaining,
+            None => return Ok(Some(calculation)),
+        }
+    }
+    Ok(None)
 }
 
 pub fn get_calculations<S: ReadonlyStorage>(
@@ -56,6 +1429,9 @@ pub fn get_calculations<S: ReadonlyStorage>(
     page: Uint128,
     page_size: Uint128,
 ) -> StdResult<(Vec<StoredCalculation>, Uint128)> {
+    let retention = get_retention_window(storage, for_address)?;
+    let state = get_history_state(storage, for_address)?;
+
     let store = ReadonlyPrefixedStorage::multilevel(
         &[PREFIX_CALCULATIONS, for_address.as_str().as_bytes()],
         storage,
@@ -63,21 +1439,27 @@ pub fn get_calculations<S: ReadonlyStorage>(
 
     // Try to access the storage of calculations for the account.
     // If it doesn't exist yet, return an empty list of calculations.
-    let store = AppendStore::<StoredCalculation, _, _>::attach(&store);
+    let store = AppendStore::<Option<StoredCalculation>, _, _>::attach(&store);
     let store = match store {
         Some(result) => result?,
         None => return Ok((vec![], Uint128::zero())),
     };
 
+    // Entries that fell out of the retention window since the account's last calculation are
+    // still in storage, but must not be returned.
+    let (start, dropped) =
+        find_retention_start(&store, &state, &retention, get_block_height(storage), 0)?;
+
     // Take `page_size` txs starting from the latest tx, potentially skipping `page * page_size`
-    // txs from the start.
-    let calculations_iter = store
-        .iter()
+    // txs from the start. Tombstones of deleted calculations are not counted.
+    let calculations_iter = (start..state.next_id)
         .rev()
+        .map(|id| store.get_at(state.slot(id, store.len())))
+        .filter_map(|calculation| calculation.transpose())
         .skip((page.u128() * page_size.u128()) as _)
         .take(page_size.u128() as _);
 
     let calculations: StdResult<Vec<StoredCalculation>> = calculations_iter.collect();
 
-    calculations.map(|txs| (txs, Uint128::from(store.len() as u128)))
+    calculations.map(|txs| (txs, Uint128::from((state.live - dropped) as u128)))
 }
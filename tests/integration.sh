@@ -130,9 +130,9 @@ function tx_of() {
     "$@" | jq -r '.txhash'
 }
 
-# Extract the output_data_as_string from the output of the command
+# Extract the output_data_as_string from the output of the command, without the spaces it's padded with
 function data_of() {
-    "$@" | jq -r '.output_data_as_string'
+    "$@" | jq -r '.output_data_as_string' | sed -E 's/ +$//'
 }
 
 function get_generic_err() {
@@ -253,12 +253,13 @@ function test_query_with_permit_after() {
     permit='{"account_number":"0","sequence":"0","chain_id":"blabla","msgs":[{"type":"query_permit","value":{"permit_name":"test","allowed_tokens":["'"$contract_addr"'"],"permissions":["calculation_history"]}}],"fee":{"amount":[{"denom":"uscrt","amount":"0"}],"gas":"1"},"memo":""}'
 
     key=a
-    expected_output='{"calculation_history":{"calcs":[{"left_operand":"23","right_operand":null,"operation":"Sqrt","result":"4"},{"left_operand":"23","right_operand":"3","operation":"Div","result":"7"},{"left_operand":"23","right_operand":"3","operation":"Mul","result":"69"}],"total":"5"}}'
+    expected_output='{"calculation_history":{"calcs":[{"id":4,"left_operand":"23","right_operand":null,"left_reference":null,"right_reference":null,"formula":null,"session":null,"memo":null,"tags":[],"effect":null,"undone":false,"operation":"Sqrt","result":"4"},{"id":3,"left_operand":"23","right_operand":"3","left_reference":null,"right_reference":null,"formula":null,"session":null,"memo":null,"tags":[],"effect":null,"undone":false,"operation":"Div","result":"7"},{"id":2,"left_operand":"23","right_operand":"3","left_reference":null,"right_reference":null,"formula":null,"session":null,"memo":null,"tags":[],"effect":null,"undone":false,"operation":"Mul","result":"69"}],"total":"5"}}'
 
     sig=$(sign_permit "$permit" "$key")
     permit_query='{"with_permit":{"query":{"calculation_history":{"page_size":"3"}},"permit":{"params":{"permit_name":"test","chain_id":"blabla","allowed_tokens":["'"$contract_addr"'"],"permissions":["calculation_history"]},"signature":'"$sig"'}}}'
     result="$(compute_query "$contract_addr" "$permit_query" 2>&1 || true )"
-    result_comparable=$(echo $result | sed 's/ Usage:.*//')
+    # block heights depend on the chain, so they are left out of the comparison
+    result_comparable=$(echo $result | sed 's/ Usage:.*//' | sed -E 's/,"block_height":[0-9]+//g')
     assert_eq "$result_comparable" "$expected_output"
     log "query result populated history: ASSERTION_SUCCESS"
 
@@ -292,7 +293,7 @@ function test_permit() {
     wrong_contract="secret148rqk0r5u6ddaf5ynfw2wagd7gyy95h2s9jlv4"
 
     permit='{"account_number":"0","sequence":"0","chain_id":"blabla","msgs":[{"type":"query_permit","value":{"permit_name":"test","allowed_tokens":["'"$wrong_contract"'"],"permissions":["calculation_history"]}}],"fee":{"amount":[{"denom":"uscrt","amount":"0"}],"gas":"1"},"memo":""}'
-    expected_error="Error: query result: encrypted: Permit doesn't apply to token \"$contract_addr\", allowed tokens: [\"$wrong_contract\"]"
+    expected_error="Error: query result: encrypted: {\"code\":23,\"msg\":\"Permit doesn't apply to token \\\"$contract_addr\\\", allowed tokens: [\\\"$wrong_contract\\\"]\"}"
 
     key=a
 
@@ -311,7 +312,7 @@ function test_permit() {
 
     # fail query due to incorrect permissions in permit
     permit='{"account_number":"0","sequence":"0","chain_id":"blabla","msgs":[{"type":"query_permit","value":{"permit_name":"test","allowed_tokens":["'"$contract_addr"'"],"permissions":["no_permissions"]}}],"fee":{"amount":[{"denom":"uscrt","amount":"0"}],"gas":"1"},"memo":""}'
-    expected_error='Error: query result: parsing calculator::msg::QueryMsg: unknown variant `no_permissions`, expected `calculation_history`'
+    expected_error='Error: query result: parsing calculator::msg::QueryMsg: unknown variant `no_permissions`, expected one of `calculation_history`, `stats`, `variables`, `sheet`, `accumulator`'
 
     sig=$(sign_permit "$permit" "$key")
     permit_query='{"with_permit":{"query":{"calculation_history":{"page_size":"3"}},"permit":{"params":{"permit_name":"test","chain_id":"blabla","allowed_tokens":["'"$contract_addr"'"],"permissions":["no_permissions"]},"signature":'"$sig"'}}}'
@@ -323,7 +324,7 @@ function test_permit() {
 
     # fail query due to mismatching signature
     permit='{"account_number":"0","sequence":"0","chain_id":"blabla","msgs":[{"type":"query_permit","value":{"permit_name":"test","allowed_tokens":["'"$contract_addr"'"],"permissions":["calculation_history"]}}],"fee":{"amount":[{"denom":"uscrt","amount":"0"}],"gas":"1"},"memo":""}'
-    expected_error='Error: query result: encrypted: Failed to verify signatures for the given permit: IncorrectSignature'
+    expected_error='Error: query result: encrypted: {"code":23,"msg":"Failed to verify signatures for the given permit: IncorrectSignature"}'
 
     sig=$(sign_permit "$permit" "$key")
     permit_query='{"with_permit":{"query":{"calculation_history":{"page_size":"3"}},"permit":{"params":{"permit_name":"test-2","chain_id":"blabla","allowed_tokens":["'"$contract_addr"'"],"permissions":["calculation_history"]},"signature":'"$sig"'}}}'
@@ -461,7 +462,7 @@ function test_div_by_zero() {
 
     log "$div_error"
 
-    local expected_error="Divisor can't be zero"
+    local expected_error='{"code":12,"msg":"Divisor can'"'"'t be zero"}'
     assert_eq "$div_error" "$expected_error"
 }
 